@@ -1,20 +1,40 @@
+use crate::pkgname::PkgName;
 use anyhow::Result;
 use serde::Deserialize;
 
+/// A result as returned by the RPC. Names are kept as strings so one bad
+/// result does not fail the whole response; validate them before use.
 #[derive(Deserialize, Debug, Clone)]
 pub struct AurPackage {
     #[serde(rename = "Name")]
     pub name: String,
+    #[serde(rename = "PackageBase")]
+    pub package_base: Option<String>,
     #[serde(rename = "Version")]
     pub version: String,
     #[serde(rename = "Maintainer")]
-    pub _maintainer: Option<String>,
+    pub maintainer: Option<String>,
+    /// Unix time the package was flagged out of date
+    #[serde(rename = "OutOfDate")]
+    pub out_of_date: Option<i64>,
     #[serde(rename = "Description")]
     pub description: Option<String>,
     #[serde(rename = "NumVotes")]
     pub num_votes: Option<i32>,
 }
 
+impl AurPackage {
+    /// The validated package name
+    pub fn pkgname(&self) -> Result<PkgName> {
+        PkgName::new(&self.name)
+    }
+
+    /// The validated pkgbase, which names the AUR git repo
+    pub fn pkgbase(&self) -> Result<PkgName> {
+        PkgName::new(self.package_base.as_deref().unwrap_or(&self.name))
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct AurResponse {
     results: Vec<AurPackage>,
@@ -52,15 +72,19 @@ async fn make_request<T: serde::Serialize + ?Sized>(
     }
 }
 
-pub async fn get_info(packages: &[String]) -> Result<Vec<AurPackage>> {
-    if packages.is_empty() {
-        return Ok(vec![]);
-    }
+/// Packages per info request, keeping the URL within the AUR's size limit
+const INFO_BATCH: usize = 150;
 
+/// Info about `packages`, queried in batches of `INFO_BATCH`
+pub async fn get_info(packages: &[PkgName]) -> Result<Vec<AurPackage>> {
     let url = "https://aur.archlinux.org/rpc/?v=5&type=info";
-    let params: Vec<(&str, &String)> = packages.iter().map(|p| ("arg[]", p)).collect();
+    let mut results = Vec::new();
 
-    make_request(url, &params).await
+    for batch in packages.chunks(INFO_BATCH) {
+        let params: Vec<(&str, &str)> = batch.iter().map(|p| ("arg[]", p.as_str())).collect();
+        results.extend(make_request(url, &params).await?);
+    }
+    Ok(results)
 }
 
 pub async fn search(query: &str) -> Result<Vec<AurPackage>> {
@@ -69,3 +93,22 @@ pub async fn search(query: &str) -> Result<Vec<AurPackage>> {
 
     make_request(url, &params).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_name_keeps_response() {
+        let json = r#"{"results": [
+            {"Name": "foo", "PackageBase": "foo-base", "Version": "1-1"},
+            {"Name": "../evil", "PackageBase": null, "Version": "1-1"}
+        ]}"#;
+        let response: AurResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.results.len(), 2);
+
+        assert_eq!(response.results[0].pkgbase().unwrap().as_str(), "foo-base");
+        assert!(response.results[1].pkgname().is_err());
+        assert!(response.results[1].pkgbase().is_err());
+    }
+}
@@ -1,6 +1,9 @@
-use alpm::{Alpm, SigLevel};
+use alpm::{Alpm, PackageReason, SigLevel};
 use anyhow::{Context, Result};
 use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
 use std::rc::Rc;
 
 pub struct ArchDB {
@@ -14,6 +17,21 @@ pub struct RepoPackage {
     pub db: String,
 }
 
+pub struct LocalPackage {
+    pub name: String,
+    /// Installed size in bytes
+    pub size: u64,
+    pub explicit: bool,
+    /// Not found in any sync database (AUR or manually installed)
+    pub foreign: bool,
+}
+
+pub struct InstalledFiles {
+    pub name: String,
+    pub base: String,
+    pub files: Vec<PathBuf>,
+}
+
 impl ArchDB {
     pub fn new() -> Result<Self> {
         let handle = Alpm::new("/", "/var/lib/pacman")?;
@@ -30,6 +48,21 @@ impl ArchDB {
         })
     }
 
+    /// Pkgbase of a package in the sync databases
+    pub fn get_repo_pkgbase(&self, pkg_name: &str) -> Option<String> {
+        self.handle
+            .syncdbs()
+            .iter()
+            .find_map(|db| db.pkg(pkg_name).ok())
+            .map(|pkg| pkg.base().unwrap_or(pkg.name()).to_string())
+    }
+
+    /// Pkgbase of an installed package
+    pub fn get_installed_pkgbase(&self, pkg_name: &str) -> Option<String> {
+        let pkg = self.handle.localdb().pkg(pkg_name).ok()?;
+        Some(pkg.base().unwrap_or(pkg.name()).to_string())
+    }
+
     pub fn exists_in_repo(&self, pkg_name: &str) -> bool {
         let dbs = self.handle.syncdbs();
         for db in dbs {
@@ -64,17 +97,7 @@ impl ArchDB {
         let mut foreign_pkgs = Vec::new();
 
         for pkg in local_db.pkgs() {
-            let pkg_name = pkg.name();
-            let mut found = false;
-
-            for db in sync_dbs {
-                if db.pkg(pkg_name).is_ok() {
-                    found = true;
-                    break;
-                }
-            }
-
-            if !found {
+            if !sync_dbs.iter().any(|db| db.pkg(pkg.name()).is_ok()) {
                 foreign_pkgs.push(RepoPackage {
                     name: pkg.name().to_string(),
                     version: pkg.version().to_string(),
@@ -86,6 +109,117 @@ impl ArchDB {
 
         Ok(foreign_pkgs)
     }
+
+    /// All installed packages
+    pub fn get_local_packages(&self) -> Vec<LocalPackage> {
+        let sync_dbs = self.handle.syncdbs();
+        self.handle
+            .localdb()
+            .pkgs()
+            .iter()
+            .map(|pkg| LocalPackage {
+                name: pkg.name().to_string(),
+                size: pkg.isize().max(0) as u64,
+                explicit: pkg.reason() == PackageReason::Explicit,
+                foreign: !sync_dbs.iter().any(|db| db.pkg(pkg.name()).is_ok()),
+            })
+            .collect()
+    }
+
+    /// Unique pkgbases of all installed foreign packages
+    pub fn get_foreign_pkgbases(&self) -> Vec<String> {
+        let sync_dbs = self.handle.syncdbs();
+        let mut bases: Vec<String> = self
+            .handle
+            .localdb()
+            .pkgs()
+            .iter()
+            .filter(|pkg| !sync_dbs.iter().any(|db| db.pkg(pkg.name()).is_ok()))
+            .map(|pkg| pkg.base().unwrap_or(pkg.name()).to_string())
+            .collect();
+        bases.sort();
+        bases.dedup();
+        bases
+    }
+
+    /// List the regular files owned by each foreign package, as absolute paths
+    pub fn get_foreign_package_files(&self) -> Vec<InstalledFiles> {
+        let local_db = self.handle.localdb();
+        let sync_dbs = self.handle.syncdbs();
+
+        local_db
+            .pkgs()
+            .iter()
+            .filter(|pkg| !sync_dbs.iter().any(|db| db.pkg(pkg.name()).is_ok()))
+            .map(|pkg| InstalledFiles {
+                name: pkg.name().to_string(),
+                base: pkg.base().unwrap_or(pkg.name()).to_string(),
+                files: pkg
+                    .files()
+                    .files()
+                    .iter()
+                    .map(|f| String::from_utf8_lossy(f.name()).to_string())
+                    .filter(|name| !name.ends_with('/'))
+                    .map(|name| PathBuf::from("/").join(name))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Collect the basenames of every shared library (`*.so*`) owned by an
+    /// installed package. A `DT_NEEDED` entry missing from this set is broken.
+    pub fn get_provided_libraries(&self) -> HashSet<String> {
+        let mut libs = HashSet::new();
+
+        for pkg in self.handle.localdb().pkgs() {
+            for file in pkg.files().files() {
+                let name = String::from_utf8_lossy(file.name());
+                if let Some(base) = name.rsplit('/').next()
+                    && base.contains(".so")
+                {
+                    libs.insert(base.to_string());
+                }
+            }
+        }
+
+        libs
+    }
+
+    /// Installed packages no other package requires or optionally requires,
+    /// that were installed as dependencies
+    pub fn get_orphans(&self) -> Vec<String> {
+        let mut orphans: Vec<String> = self
+            .handle
+            .localdb()
+            .pkgs()
+            .iter()
+            .filter(|pkg| pkg.reason() == PackageReason::Depend)
+            .filter(|pkg| pkg.required_by().is_empty() && pkg.optional_for().is_empty())
+            .map(|pkg| pkg.name().to_string())
+            .collect();
+        orphans.sort();
+        orphans
+    }
+
+    /// Whether `pkg_name` is installed with the reason "dependency"
+    pub fn is_installed_as_dep(&self, pkg_name: &str) -> bool {
+        let local_db = self.handle.localdb();
+        local_db
+            .pkg(pkg_name)
+            .is_ok_and(|p| p.reason() == PackageReason::Depend)
+    }
+
+    /// Installed version of `pkgbase`, taken from any package built from it
+    /// (split pkgbases usually have no package of the same name)
+    pub fn get_installed_base_version(&self, pkgbase: &str) -> Option<String> {
+        self.handle
+            .localdb()
+            .pkgs()
+            .iter()
+            .find(|pkg| pkg.base().unwrap_or(pkg.name()) == pkgbase)
+            .map(|pkg| pkg.version().to_string())
+    }
+
     pub fn get_installed_version(&self, pkg_name: &str) -> Option<String> {
         let local_db = self.handle.localdb();
         local_db.pkg(pkg_name).ok().map(|p| p.version().to_string())
@@ -95,3 +229,52 @@ impl ArchDB {
         alpm::vercmp(v1, v2)
     }
 }
+
+/// Architectures pacman installs packages for (`Architecture` in
+/// pacman.conf), or the machine architecture if it cannot be read
+pub fn system_architectures() -> Vec<String> {
+    let arches: Vec<String> = Command::new("pacman-conf")
+        .arg("Architecture")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .filter(|arch| *arch != "auto")
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if arches.is_empty() {
+        vec![std::env::consts::ARCH.to_string()]
+    } else {
+        arches
+    }
+}
+
+/// Whether a package with the given `arch=` can be built for `system`
+pub fn supports_arch(pkg_arch: &[String], system: &[String]) -> bool {
+    pkg_arch.is_empty()
+        || pkg_arch
+            .iter()
+            .any(|arch| arch == "any" || system.contains(arch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports_arch() {
+        let system = vec!["aarch64".to_string()];
+        assert!(supports_arch(&["any".to_string()], &system));
+        assert!(supports_arch(
+            &["x86_64".to_string(), "aarch64".to_string()],
+            &system
+        ));
+        assert!(!supports_arch(&["x86_64".to_string()], &system));
+        assert!(supports_arch(&[], &system));
+    }
+}
@@ -1,50 +1,122 @@
 use anyhow::{Context, Result};
 use colored::*;
 use log::debug;
-use std::env;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use crate::git_ops;
 use crate::interactive;
+use crate::pkgname::PkgName;
 
+/// Run the PKGBUILD's `pkgver()` (`makepkg --nobuild`) to compute the
+/// current version of a VCS package. The PKGBUILD rewritten by makepkg is
+/// restored afterwards so the clone stays clean.
+pub fn resolve_vcs_version(
+    pkg: &PkgName,
+    cache_dir: &Path,
+    config: &crate::config::Config,
+) -> Result<String> {
+    println!(":: Determining latest version of {}...", pkg.cyan());
+
+    // Sources must verify, so make sure the PGP keys are available first
+    if let Ok(metadata) = crate::parser::parse_srcinfo(cache_dir)
+        && !metadata.validpgpkeys.is_empty()
+    {
+        crate::gpg::ensure_keys(&metadata.validpgpkeys, cache_dir, config)?;
+    }
+
+    let pkgbuild_path = cache_dir.join("PKGBUILD");
+    let pkgbuild = std::fs::read(&pkgbuild_path)
+        .with_context(|| format!("Failed to read {}", pkgbuild_path.display()))?;
+
+    // Only pkgver() runs, so the architecture does not matter yet
+    let status = crate::privilege::makepkg(config)?
+        .args(["--nobuild", "--nodeps", "--noprepare", "--ignorearch"])
+        .current_dir(cache_dir)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .context("Failed to execute makepkg --nobuild")?;
+
+    let output = crate::privilege::makepkg(config)?
+        .arg("--printsrcinfo")
+        .current_dir(cache_dir)
+        .output()
+        .context("Failed to run makepkg --printsrcinfo");
+
+    // Undo the pkgver bump makepkg wrote into the PKGBUILD, keeping patches
+    // and local edits
+    std::fs::write(&pkgbuild_path, &pkgbuild)
+        .with_context(|| format!("Failed to restore {}", pkgbuild_path.display()))?;
+
+    if !status.success() {
+        anyhow::bail!("makepkg --nobuild failed for {}", pkg);
+    }
+
+    let output = output?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("makepkg --printsrcinfo failed:\n{}", err.trim());
+    }
+
+    let version =
+        crate::parser::parse_srcinfo_str(&String::from_utf8_lossy(&output.stdout))?.version;
+    debug!("Resolved VCS version of {}: {}", pkg, version);
+    Ok(version)
+}
+
+/// Build `pkg` from its clone in the cache dir. With `review`, the diff and
+/// PKGBUILD review prompts are shown before building. With `force`, packages
+/// of the same version built earlier are overwritten (`makepkg -f`), e.g.
+/// for soname rebuilds. `local_deps` are the packages built earlier in this
+/// run, installed into the chroot for `chroot = true` builds.
 pub fn build_package(
-    pkg: &str,
+    pkg: &PkgName,
     config: &crate::config::Config,
-    show_diff: bool,
+    review: bool,
+    force: bool,
+    local_deps: &[PathBuf],
 ) -> Result<Vec<PathBuf>> {
     let cache_base = config.get_cache_dir();
     let cache_dir = cache_base.join(pkg);
     let cache_path = Path::new(&cache_dir);
+    let pkg_config = config.package(pkg);
+    let review = review && !pkg_config.skip_review;
+
+    // makechrootpkg offers no way to pass the environment into the chroot
+    if pkg_config.chroot && !pkg_config.env.is_empty() {
+        anyhow::bail!(
+            "{} sets both `chroot` and `env`, but env overrides cannot be passed into the chroot. \
+             Remove one of them from [packages.{}].",
+            pkg,
+            pkg
+        );
+    }
 
     println!(":: Building {}...", pkg.cyan());
 
-    // 1. Prompt for diff if requested (and if it's an update, which implies directory exists)
-    // I hope the directory exists, otherwise this will look silly.
-    if show_diff && !config.no_confirm && cache_path.exists() && interactive::prompt_diff(pkg)? {
-        match git_ops::get_diff(cache_path) {
+    // 1. Prompt for diff against the last reviewed commit, if there is anything new
+    let mut reviewed = false;
+    if review
+        && config.diff_viewer
+        && !config.no_confirm
+        && cache_path.exists()
+        && interactive::prompt_diff(pkg)?
+    {
+        reviewed = true;
+        let diff = git_ops::get_diff(cache_path).and_then(|mut diff| {
+            let patched = git_ops::get_local_changes(cache_path)?;
+            if !patched.is_empty() {
+                diff.push_str(&format!("{}\n", ":: Local patches".bold()));
+                diff.push_str(&patched);
+            }
+            Ok(diff)
+        });
+        match diff {
             Ok(diff) => {
                 if diff.is_empty() {
-                    println!(":: No git changes found.");
+                    println!(":: No changes since last review.");
                 } else {
-                    // Use pager for diff
-                    match Command::new("less")
-                        .arg("-R") // Raw control chars for color
-                        .stdin(std::process::Stdio::piped())
-                        .spawn()
-                    {
-                        Ok(mut pager) => {
-                            if let Some(mut stdin) = pager.stdin.take() {
-                                use std::io::Write;
-                                let _ = write!(stdin, "{}", diff);
-                            }
-                            pager.wait()?;
-                        }
-                        Err(_) => {
-                            println!(":: (Pager failed, showing raw diff)");
-                            println!("{}", diff);
-                        }
-                    }
+                    interactive::show_in_pager(&diff)?;
                 }
             }
             Err(e) => println!(":: Failed to get diff: {}", e),
@@ -52,31 +124,9 @@ pub fn build_package(
     }
 
     // 2. Prompt for review (skip in --noconfirm mode)
-    if !config.no_confirm && interactive::prompt_review(pkg)? {
-        let editor = config
-            .editor
-            .as_deref()
-            .map(|s| s.to_string())
-            .or_else(|| env::var("EDITOR").ok())
-            .unwrap_or_else(|| "nano".to_string());
-
-        let pkgbuild_path = cache_path.join("PKGBUILD");
-        // let pkgbuild_str = pkgbuild_path.to_string_lossy();
-
-        // Use sh -c to allow arguments in EDITOR (e.g., "code --wait")
-        // Pass the file path as an argument to sh to prevent shell injection
-        let status = Command::new("sh")
-            .arg("-c")
-            .arg(format!("{} \"$1\"", editor))
-            .arg("--")
-            .arg(&pkgbuild_path)
-            .status()
-            .context(format!("Failed to open editor: {}", editor))?;
-
-        if !status.success() {
-            println!("{}", "!! Editor exited with error.".red());
-            anyhow::bail!("Editor failed with status: {}", status);
-        }
+    if review && !config.no_confirm && interactive::prompt_review(pkg)? {
+        reviewed = true;
+        interactive::open_editor(&config.get_editor(), &[cache_path.join("PKGBUILD")])?;
 
         // Post-edit confirmation (fixes issue where editors return immediately)
         if !config.no_confirm && !crate::interactive::prompt_continue()? {
@@ -84,27 +134,13 @@ pub fn build_package(
         }
     }
 
-    // 3. Get exact list of packages that will be built BEFORE building
-    println!(":: Determining package list...");
-    let packagelist_output = Command::new("makepkg")
-        .arg("--packagelist")
-        .current_dir(&cache_dir)
-        .output()
-        .context("Failed to run makepkg --packagelist")?;
-
-    if !packagelist_output.status.success() {
-        let err = String::from_utf8_lossy(&packagelist_output.stderr);
-        anyhow::bail!("makepkg --packagelist failed:\n{}", err.trim());
+    if reviewed {
+        git_ops::mark_reviewed(cache_path)?;
     }
 
-    let package_files: Vec<PathBuf> = String::from_utf8_lossy(&packagelist_output.stdout)
-        .lines()
-        .map(|line| PathBuf::from(line.trim()))
-        .collect();
-
-    if package_files.is_empty() {
-        anyhow::bail!("makepkg --packagelist returned no packages");
-    }
+    // 3. Get exact list of packages that will be built BEFORE building
+    println!(":: Determining package list...");
+    let package_files = package_list(config, &pkg_config, cache_path)?;
 
     println!(":: Will build: {}", package_files.len());
     for pf in &package_files {
@@ -121,6 +157,8 @@ pub fn build_package(
             .args(["clean", "-fdx"])
             .status()
             .context("Failed to clean build directory")?;
+        // Files added by patches were removed along with the build artifacts
+        git_ops::apply_patches(cache_path, &pkg_config.patch_paths())?;
     }
 
     // 5. Fetch required PGP keys from .SRCINFO
@@ -128,36 +166,49 @@ pub fn build_package(
     if let Ok(metadata) = crate::parser::parse_srcinfo(cache_path)
         && !metadata.validpgpkeys.is_empty()
     {
-        let keys_ok = crate::gpg::ensure_keys(&metadata.validpgpkeys)?;
+        let keys_ok = crate::gpg::ensure_keys(&metadata.validpgpkeys, cache_path, config)?;
         if !keys_ok {
-            eprintln!(
-                "{} GPG key fetch failed — falling back to --skippgpcheck",
-                "!!".yellow().bold()
-            );
+            crate::gpg::confirm_skip_pgp(pkg, config)?;
             skip_pgp = true;
         }
     }
 
     // 6. Run makepkg
     debug!("Starting makepkg for {}", pkg);
-    let mut makepkg = Command::new("makepkg");
-    
-    // Base flags: Sync deps, Remove deps
-    let mut flags = "-sr".to_string();
-    if config.clean_build {
-        flags.push('f'); // Force build if cleaning
-    }
-    makepkg.arg(flags);
-    if skip_pgp {
-        makepkg.arg("--skippgpcheck");
-    }
-    let status = makepkg
-        .current_dir(&cache_dir)
-        .status()
-        .context("Failed to execute makepkg")?;
+    let build_started = std::time::SystemTime::now();
+    let status = if pkg_config.chroot {
+        chroot_build(&cache_dir, config, &pkg_config, skip_pgp, force, local_deps)?
+    } else {
+        let mut makepkg = build_command(config, &pkg_config)?;
+
+        // Base flags: Sync deps, Remove deps
+        let mut flags = "-sr".to_string();
+        if config.clean_build || force {
+            flags.push('f'); // Overwrite packages built earlier
+        }
+        makepkg.arg(flags);
+        if skip_pgp {
+            makepkg.arg("--skippgpcheck");
+        }
+        makepkg
+            .current_dir(&cache_dir)
+            .status()
+            .context("Failed to execute makepkg")?
+    };
 
     if status.success() {
         println!(":: {} {}", pkg.green(), "built successfully!".green());
+        git_ops::mark_reviewed(cache_path)?;
+
+        // pkgver() of VCS packages changed the version during the build
+        let package_files = package_list(config, &pkg_config, cache_path)
+            .unwrap_or(package_files)
+            .into_iter()
+            .map(|path| locate_artifact(path, build_started))
+            .collect::<Vec<_>>();
+
+        // The built files must exist and match the reviewed .SRCINFO
+        crate::verify::check_artifacts(&package_files, cache_path, config)?;
 
         // Return the exact package files that were built
         Ok(package_files)
@@ -165,3 +216,138 @@ pub fn build_package(
         anyhow::bail!("Failed to build {}. Aborting queue.", pkg);
     }
 }
+
+/// Package files `makepkg --packagelist` expects the build to produce
+fn package_list(
+    config: &crate::config::Config,
+    pkg_config: &crate::config::PackageConfig,
+    cache_path: &Path,
+) -> Result<Vec<PathBuf>> {
+    // Same flags and environment as the build, so the predicted paths match
+    let output = build_command(config, pkg_config)?
+        .arg("--packagelist")
+        .current_dir(cache_path)
+        .output()
+        .context("Failed to run makepkg --packagelist")?;
+
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("makepkg --packagelist failed:\n{}", err.trim());
+    }
+
+    let package_files: Vec<PathBuf> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| PathBuf::from(line.trim()))
+        .collect();
+
+    if package_files.is_empty() {
+        anyhow::bail!("makepkg --packagelist returned no packages");
+    }
+    Ok(package_files)
+}
+
+/// The file actually built for the predicted `path`: itself if it exists,
+/// otherwise the newest package of the same name written since `since`,
+/// e.g. when a chroot build bumped pkgver without updating our PKGBUILD
+fn locate_artifact(path: PathBuf, since: std::time::SystemTime) -> PathBuf {
+    if path.exists() {
+        return path;
+    }
+    let (Some(dir), Some(pkgname)) = (
+        path.parent(),
+        path.file_name()
+            .and_then(|f| crate::clean::artifact_pkgname(&f.to_string_lossy())),
+    ) else {
+        return path;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return path;
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            crate::clean::artifact_pkgname(&entry.file_name().to_string_lossy()).as_ref()
+                == Some(&pkgname)
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            (modified >= since).then(|| (modified, entry.path()))
+        })
+        .max()
+        .map(|(_, found)| found)
+        .unwrap_or(path)
+}
+
+/// makepkg with the global and per-package flags and environment
+fn build_command(
+    config: &crate::config::Config,
+    pkg_config: &crate::config::PackageConfig,
+) -> Result<Command> {
+    let mut cmd = crate::privilege::makepkg(config)?;
+    cmd.args(&config.makepkg_flags)
+        .args(&pkg_config.makepkg_flags)
+        .envs(&pkg_config.env);
+    if config.ignore_arch || pkg_config.ignore_arch {
+        cmd.arg("--ignorearch");
+    }
+    Ok(cmd)
+}
+
+/// Build in a clean chroot with devtools' `makechrootpkg`, creating the
+/// chroot with `mkarchroot` on first use
+fn chroot_build(
+    cache_dir: &Path,
+    config: &crate::config::Config,
+    pkg_config: &crate::config::PackageConfig,
+    skip_pgp: bool,
+    force: bool,
+    local_deps: &[PathBuf],
+) -> Result<std::process::ExitStatus> {
+    if !crate::privilege::command_exists("makechrootpkg") {
+        anyhow::bail!("chroot builds require makechrootpkg. Please install devtools.");
+    }
+
+    let chroot_dir = config
+        .chroot_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| crate::config::Config::get_data_dir().join("chroot"));
+
+    if !chroot_dir.join("root").exists() {
+        println!(
+            ":: Creating build chroot in {}...",
+            chroot_dir.display().to_string().cyan()
+        );
+        std::fs::create_dir_all(&chroot_dir)?;
+        let status = crate::privilege::root_command(config, "mkarchroot")?
+            .arg(chroot_dir.join("root"))
+            .arg("base-devel")
+            .status()
+            .context("Failed to execute mkarchroot")?;
+        if !status.success() {
+            anyhow::bail!("Failed to create chroot in {:?}", chroot_dir);
+        }
+    }
+
+    let mut cmd = Command::new("makechrootpkg");
+    cmd.env("GNUPGHOME", crate::gpg::keyring_dir(config)?);
+    cmd.arg("-c").arg("-r").arg(&chroot_dir);
+    for dep in local_deps {
+        cmd.arg("-I").arg(dep);
+    }
+    cmd.arg("--");
+    if skip_pgp {
+        cmd.arg("--skippgpcheck");
+    }
+    if force {
+        cmd.arg("-f");
+    }
+    if config.ignore_arch || pkg_config.ignore_arch {
+        cmd.arg("--ignorearch");
+    }
+    cmd.args(&config.makepkg_flags)
+        .args(&pkg_config.makepkg_flags)
+        .current_dir(cache_dir)
+        .status()
+        .context("Failed to execute makechrootpkg")
+}
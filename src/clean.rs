@@ -0,0 +1,297 @@
+use crate::arch::ArchDB;
+use crate::config::Config;
+use crate::interactive;
+use anyhow::{Context, Result};
+use colored::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Something in the build dir that cleaning would delete
+#[derive(Debug)]
+pub struct CleanItem {
+    pub path: PathBuf,
+    pub size: u64,
+    pub reason: &'static str,
+}
+
+/// Work out what to delete from the build dir at `cache_dir`:
+/// clones of pkgbases not in `installed`, `src/` and `pkg/` work dirs of
+/// the remaining clones and all but the newest `keep` artifacts per package.
+/// Only clones of AUR or packaging repos are touched, see `is_packaging_clone`.
+pub fn plan(cache_dir: &Path, installed: &HashSet<String>, keep: usize) -> Result<Vec<CleanItem>> {
+    let mut items = Vec::new();
+    if !cache_dir.exists() {
+        return Ok(items);
+    }
+
+    let mut clones: Vec<PathBuf> = fs::read_dir(cache_dir)
+        .with_context(|| format!("Failed to read {:?}", cache_dir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.join(".git").is_dir() && is_packaging_clone(path))
+        .collect();
+    clones.sort();
+
+    for clone in clones {
+        let pkgbase = clone.file_name().unwrap_or_default().to_string_lossy();
+        if !installed.contains(pkgbase.as_ref()) {
+            items.push(CleanItem {
+                size: dir_size(&clone),
+                path: clone,
+                reason: "not installed",
+            });
+            continue;
+        }
+
+        for work_dir in ["src", "pkg"] {
+            let path = clone.join(work_dir);
+            if path.is_dir() {
+                items.push(CleanItem {
+                    size: dir_size(&path),
+                    path,
+                    reason: "work dir",
+                });
+            }
+        }
+
+        for path in old_artifacts(&clone, keep)? {
+            items.push(CleanItem {
+                size: dir_size(&path),
+                path,
+                reason: "old package",
+            });
+        }
+    }
+
+    Ok(items)
+}
+
+/// Whether the repo at `path` was cloned by ax or `-G`: its origin must be
+/// the AUR or packaging repo named like the directory. Anything else in the
+/// build dir may be the user's own work.
+fn is_packaging_clone(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    git2::Repository::open(path)
+        .ok()
+        .and_then(|repo| repo.find_remote("origin").ok()?.url().map(String::from))
+        .is_some_and(|url| crate::getpkgbuild::is_packaging_url(name, &url))
+}
+
+/// Built packages in `dir` beyond the newest `keep` per package name,
+/// together with their signatures
+fn old_artifacts(dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let mut by_name: HashMap<String, Vec<(SystemTime, PathBuf)>> = HashMap::new();
+
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(name) = artifact_pkgname(&file_name) else {
+            continue;
+        };
+        let mtime = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        by_name.entry(name).or_default().push((mtime, path));
+    }
+
+    let mut old = Vec::new();
+    for (_, mut artifacts) in by_name {
+        artifacts.sort_by_key(|(mtime, _)| std::cmp::Reverse(*mtime));
+        for (_, path) in artifacts.into_iter().skip(keep) {
+            let sig = PathBuf::from(format!("{}.sig", path.display()));
+            if sig.exists() {
+                old.push(sig);
+            }
+            old.push(path);
+        }
+    }
+    old.sort();
+    Ok(old)
+}
+
+/// Package name of a `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.*` file
+pub fn artifact_pkgname(file_name: &str) -> Option<String> {
+    if file_name.ends_with(".sig") {
+        return None;
+    }
+    let (stem, _) = file_name.split_once(".pkg.tar")?;
+    let mut parts = stem.rsplitn(4, '-');
+    let (_arch, _pkgrel, _pkgver) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next().map(String::from)
+}
+
+/// `[epoch:]pkgver-pkgrel` of a `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.*` file
+pub fn artifact_version(file_name: &str) -> Option<String> {
+    if file_name.ends_with(".sig") {
+        return None;
+    }
+    let (stem, _) = file_name.split_once(".pkg.tar")?;
+    let mut parts = stem.rsplitn(4, '-');
+    let (_arch, pkgrel, pkgver) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next()?;
+    Some(format!("{}-{}", pkgver, pkgrel))
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|e| dir_size(&e.path())).sum())
+        .unwrap_or(0)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+/// Clean the AUR build dir (`-Sc`/`-Scc`), keeping `keep` artifacts per package
+pub fn clean_build_dir(config: &Config, keep: usize, dry_run: bool) -> Result<()> {
+    let cache_dir = config.get_cache_dir();
+    println!(
+        "\n{} {}",
+        ":: AUR build directory:".blue().bold(),
+        cache_dir.display()
+    );
+
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    let installed: HashSet<String> = arch_db.get_foreign_pkgbases().into_iter().collect();
+    let items = plan(&cache_dir, &installed, keep)?;
+
+    if items.is_empty() {
+        println!(":: Nothing to clean.");
+        return Ok(());
+    }
+
+    for item in &items {
+        let path = item.path.strip_prefix(&cache_dir).unwrap_or(&item.path);
+        println!(
+            "   {} {} ({})",
+            path.display(),
+            format_size(item.size).cyan(),
+            item.reason
+        );
+    }
+    let total: u64 = items.iter().map(|item| item.size).sum();
+
+    if dry_run {
+        println!(":: Would reclaim {}", format_size(total).green().bold());
+        return Ok(());
+    }
+    if !config.no_confirm && !interactive::prompt_confirm("Remove these files?")? {
+        return Ok(());
+    }
+
+    let mut reclaimed = 0;
+    for item in &items {
+        let result = if item.path.is_dir() {
+            fs::remove_dir_all(&item.path)
+        } else {
+            fs::remove_file(&item.path)
+        };
+        match result {
+            Ok(()) => reclaimed += item.size,
+            Err(e) => eprintln!(
+                "{} Failed to remove {}: {}",
+                "!!".red().bold(),
+                item.path.display(),
+                e
+            ),
+        }
+    }
+
+    println!(":: Reclaimed {}", format_size(reclaimed).green().bold());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let tmp = crate::tempdir::TempDir::new("ax-clean-test").unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        let clones = [
+            ("installed", Some("https://aur.archlinux.org/installed.git")),
+            ("removed", Some("https://aur.archlinux.org/removed.git")),
+            (
+                "linux",
+                Some("https://gitlab.archlinux.org/archlinux/packaging/packages/linux.git"),
+            ),
+            // The user's own repos are never touched
+            ("project", Some("https://example.com/project.git")),
+            ("other", Some("https://aur.archlinux.org/foo.git")),
+            ("local", None),
+        ];
+        for (clone, origin) in clones {
+            let repo = git2::Repository::init(dir.join(clone)).unwrap();
+            if let Some(url) = origin {
+                repo.remote("origin", url).unwrap();
+            }
+        }
+        let installed_dir = dir.join("installed");
+        fs::create_dir_all(installed_dir.join("src")).unwrap();
+        for (i, version) in ["1.0-1", "1.1-1", "1.2-1"].iter().enumerate() {
+            let file = installed_dir.join(format!("installed-{}-x86_64.pkg.tar.zst", version));
+            fs::write(&file, "x").unwrap();
+            let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(i as u64 * 60);
+            fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+
+        let installed = HashSet::from(["installed".to_string()]);
+        let items = plan(&dir, &installed, 1).unwrap();
+        let paths: Vec<String> = items
+            .iter()
+            .map(|item| item.path.strip_prefix(&dir).unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "installed/src",
+                "installed/installed-1.0-1-x86_64.pkg.tar.zst",
+                "installed/installed-1.1-1-x86_64.pkg.tar.zst",
+                "linux",
+                "removed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_artifact_pkgname() {
+        assert_eq!(
+            artifact_pkgname("foo-bar-1.2-3-x86_64.pkg.tar.zst").as_deref(),
+            Some("foo-bar")
+        );
+        assert_eq!(artifact_pkgname("foo-1.2-3-any.pkg.tar.zst.sig"), None);
+        assert_eq!(artifact_pkgname("PKGBUILD"), None);
+        assert_eq!(
+            artifact_version("foo-bar-1:1.2-3-x86_64.pkg.tar.zst").as_deref(),
+            Some("1:1.2-3")
+        );
+    }
+}
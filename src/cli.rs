@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(name = "ax", version)]
@@ -7,6 +7,10 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Commands>,
 
+    /// Override a config setting for this invocation (repeatable)
+    #[arg(long = "ax-config", global = true, value_name = "KEY=VALUE")]
+    pub config_overrides: Vec<String>,
+
     /// Search query
     #[arg(trailing_var_arg = true)]
     pub query: Vec<String>,
@@ -16,21 +20,112 @@ pub struct Cli {
 pub enum Commands {
     #[command(short_flag = 'S')]
     Sync {
-        #[arg(short = 'y', long)]
-        refresh: bool, // -y
-        #[arg(short = 'u', long)]
-        sysupgrade: bool, // -u
+        #[command(flatten)]
+        sync: SyncOptions,
+        #[command(flatten)]
+        common: CommonOptions,
+        #[command(flatten)]
+        transaction: TransactionOptions,
+        #[command(flatten)]
+        upgrade: UpgradeOptions,
+
         #[arg(long)]
         cleanbuild: bool,
+        /// Rebuild AUR packages linked against sonames that are no longer installed
+        #[arg(long)]
+        rebuildcheck: bool,
+        /// Check upstream VCS sources of devel (-git, ...) packages for updates
+        #[arg(long)]
+        devel: bool,
+        /// Run pkgver() of VCS packages to check whether they are up to date
+        #[arg(long)]
+        vcspkgver: bool,
+        /// Keep sudo credentials fresh in the background during the transaction
+        #[arg(long)]
+        sudoloop: bool,
+        /// Seed the devel database from installed AUR packages
+        #[arg(long)]
+        gendb: bool,
+        /// Extra flags passed to makepkg, e.g. --mflags "--nocheck --holdver"
+        #[arg(long, value_name = "FLAGS", allow_hyphen_values = true)]
+        mflags: Option<String>,
+        /// makepkg.conf to build with
+        #[arg(long, value_name = "PATH")]
+        makepkg_conf: Option<String>,
+        /// makepkg binary to build with
+        #[arg(long, value_name = "BIN")]
+        makepkg: Option<String>,
+        /// With -Sc, only show what would be removed from the AUR build dir
+        #[arg(long)]
+        dry_run: bool,
+        /// Directory built packages are written to (PKGDEST), e.g. with -Sw
+        #[arg(long, value_name = "DIR")]
+        pkgdest: Option<String>,
+        /// Reset AUR clones with local changes or diverged history without asking
+        #[arg(long)]
+        reset_clones: bool,
+        /// Build AUR packages whose `arch` does not include the system architecture
+        #[arg(long)]
+        ignorearch: bool,
 
         /// Packages to install
-        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         packages: Vec<String>,
     },
+    /// ax-specific maintenance operations
+    #[command(short_flag = 'Y')]
+    Helper {
+        /// Remove orphaned dependencies, including AUR make dependencies ax installed
+        #[arg(short = 'c', long)]
+        clean: bool,
+    },
+    /// Show statistics about installed packages
+    #[command(short_flag = 'P', long_flag = "show", visible_alias = "stats")]
+    Show {
+        /// Print package statistics (the default)
+        #[arg(short = 's', long)]
+        stats: bool,
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Reinstall a cached build or rebuild an older AUR commit of a package
+    #[command(long_flag = "downgrade")]
+    Downgrade {
+        /// Installed AUR package
+        package: String,
+    },
+    /// List the runs in the history log, or undo one
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+    },
+    /// Clone the packaging repos of the targets into the current directory
+    #[command(short_flag = 'G', long_flag = "getpkgbuild")]
+    GetPkgbuild {
+        /// Repo or AUR packages
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
     #[command(short_flag = 'R')]
     Remove {
+        /// Remove packages and all packages that depend on them
+        #[arg(short = 'c', long)]
+        cascade: bool,
+        /// Remove configuration files
+        #[arg(short = 'n', long)]
+        nosave: bool,
+        /// Remove unnecessary dependencies (-ss includes explicitly installed ones)
+        #[arg(short = 's', long, action = ArgAction::Count)]
+        recursive: u8,
+        /// Remove unneeded packages
+        #[arg(short = 'u', long)]
+        unneeded: bool,
+        #[command(flatten)]
+        common: CommonOptions,
+        #[command(flatten)]
+        transaction: TransactionOptions,
+
         /// Packages to remove
-        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         packages: Vec<String>,
     },
     #[command(short_flag = 'Q')]
@@ -59,8 +154,298 @@ pub enum Commands {
     },
     #[command(short_flag = 'U')]
     Upgrade {
-        /// Arguments to pass to pacman -U
-        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
-        args: Vec<String>,
+        #[command(flatten)]
+        common: CommonOptions,
+        #[command(flatten)]
+        transaction: TransactionOptions,
+        #[command(flatten)]
+        upgrade: UpgradeOptions,
+
+        /// Package files or URLs to install
+        files: Vec<String>,
     },
+    /// View or modify the ax configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HistoryAction {
+    /// Reinstall the packages a run replaced and remove the ones it added
+    Undo {
+        /// Run id as shown by `ax history`
+        id: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// List all settings and their values
+    List {
+        /// Show which layer (default, system, user, env, command line) set each value
+        #[arg(long)]
+        origin: bool,
+    },
+    /// Print the value of a setting
+    Get { key: String },
+    /// Change a setting in the user's config.toml
+    Set {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+    },
+    /// Open the user's config.toml in the configured editor
+    Edit,
+    /// Print the path of the user's config.toml
+    Path,
+}
+
+/// Sync operation flags (`-S` plus one of these)
+#[derive(Args, Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Remove old packages from the cache (-cc removes all)
+    #[arg(short = 'c', long, action = ArgAction::Count)]
+    pub clean: u8,
+    /// View all members of a package group
+    #[arg(short = 'g', long, action = ArgAction::Count)]
+    pub groups: u8,
+    /// View package information (-ii for extended information)
+    #[arg(short = 'i', long, action = ArgAction::Count)]
+    pub info: u8,
+    /// View a list of packages in a repo
+    #[arg(short = 'l', long)]
+    pub list: bool,
+    /// Show less information for query and search
+    #[arg(short = 'q', long)]
+    pub quiet: bool,
+    /// Search remote repositories and the AUR for matching strings
+    #[arg(short = 's', long)]
+    pub search: bool,
+    /// Upgrade installed packages (-uu enables downgrades)
+    #[arg(short = 'u', long, action = ArgAction::Count)]
+    pub sysupgrade: u8,
+    /// Download packages but do not install
+    #[arg(short = 'w', long)]
+    pub downloadonly: bool,
+    /// Download fresh package databases (-yy to force a refresh)
+    #[arg(short = 'y', long, action = ArgAction::Count)]
+    pub refresh: u8,
+}
+
+impl SyncOptions {
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        push_count(&mut args, "--clean", self.clean);
+        push_count(&mut args, "--groups", self.groups);
+        push_count(&mut args, "--info", self.info);
+        push_flag(&mut args, "--list", self.list);
+        push_flag(&mut args, "--quiet", self.quiet);
+        push_flag(&mut args, "--search", self.search);
+        push_count(&mut args, "--sysupgrade", self.sysupgrade);
+        push_flag(&mut args, "--downloadonly", self.downloadonly);
+        push_count(&mut args, "--refresh", self.refresh);
+        args
+    }
+}
+
+/// pacman options accepted by every operation
+#[derive(Args, Debug, Default, Clone)]
+pub struct CommonOptions {
+    /// Alternate database location
+    #[arg(short = 'b', long, value_name = "PATH")]
+    pub dbpath: Option<String>,
+    /// Alternate installation root
+    #[arg(short = 'r', long, value_name = "PATH")]
+    pub root: Option<String>,
+    /// Be verbose
+    #[arg(short = 'v', long)]
+    pub verbose: bool,
+    /// Alternate architecture
+    #[arg(long, value_name = "ARCH")]
+    pub arch: Option<String>,
+    /// Alternate package cache location
+    #[arg(long, value_name = "DIR")]
+    pub cachedir: Vec<String>,
+    /// Colorize the output
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<String>,
+    /// Alternate pacman.conf
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Display debug messages
+    #[arg(long)]
+    pub debug: bool,
+    /// Alternate home directory for GnuPG
+    #[arg(long, value_name = "DIR")]
+    pub gpgdir: Option<String>,
+    /// Alternate hook location
+    #[arg(long, value_name = "DIR")]
+    pub hookdir: Vec<String>,
+    /// Alternate log file
+    #[arg(long, value_name = "PATH")]
+    pub logfile: Option<String>,
+    /// Do not ask for any confirmation
+    #[arg(long, overrides_with = "confirm")]
+    pub noconfirm: bool,
+    /// Always ask for confirmation
+    #[arg(long, overrides_with = "noconfirm")]
+    pub confirm: bool,
+    /// Use relaxed timeouts for downloads
+    #[arg(long)]
+    pub disable_download_timeout: bool,
+    /// Operate on a mounted guest system (root-only)
+    #[arg(long, value_name = "DIR")]
+    pub sysroot: Option<String>,
+}
+
+impl CommonOptions {
+    /// The first option pointing pacman at another system's database, which
+    /// ax's own lookups would not follow
+    pub fn foreign_root(&self) -> Option<&'static str> {
+        [
+            ("--root", &self.root),
+            ("--dbpath", &self.dbpath),
+            ("--sysroot", &self.sysroot),
+        ]
+        .into_iter()
+        .find_map(|(flag, value)| value.as_ref().map(|_| flag))
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        push_value(&mut args, "--dbpath", &self.dbpath);
+        push_value(&mut args, "--root", &self.root);
+        push_flag(&mut args, "--verbose", self.verbose);
+        push_value(&mut args, "--arch", &self.arch);
+        push_values(&mut args, "--cachedir", &self.cachedir);
+        push_value(&mut args, "--color", &self.color);
+        push_value(&mut args, "--config", &self.config);
+        push_flag(&mut args, "--debug", self.debug);
+        push_value(&mut args, "--gpgdir", &self.gpgdir);
+        push_values(&mut args, "--hookdir", &self.hookdir);
+        push_value(&mut args, "--logfile", &self.logfile);
+        push_flag(&mut args, "--noconfirm", self.noconfirm);
+        push_flag(&mut args, "--confirm", self.confirm);
+        push_flag(
+            &mut args,
+            "--disable-download-timeout",
+            self.disable_download_timeout,
+        );
+        push_value(&mut args, "--sysroot", &self.sysroot);
+        args
+    }
+}
+
+/// Options of operations that change installed packages (-S, -R, -U)
+#[derive(Args, Debug, Default, Clone)]
+pub struct TransactionOptions {
+    /// Skip dependency version checks (-dd to skip all checks)
+    #[arg(short = 'd', long, action = ArgAction::Count)]
+    pub nodeps: u8,
+    /// Add a virtual package to satisfy dependencies
+    #[arg(long, value_name = "PKG=VER")]
+    pub assume_installed: Vec<String>,
+    /// Only modify database entries, not package files
+    #[arg(long)]
+    pub dbonly: bool,
+    /// Do not show a progress bar when downloading files
+    #[arg(long)]
+    pub noprogressbar: bool,
+    /// Do not execute the install scriptlet if one exists
+    #[arg(long)]
+    pub noscriptlet: bool,
+    /// Print the targets instead of performing the operation
+    #[arg(short = 'p', long)]
+    pub print: bool,
+    /// Specify how the targets should be printed
+    #[arg(long, value_name = "FORMAT")]
+    pub print_format: Option<String>,
+}
+
+impl TransactionOptions {
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        push_count(&mut args, "--nodeps", self.nodeps);
+        push_values(&mut args, "--assume-installed", &self.assume_installed);
+        push_flag(&mut args, "--dbonly", self.dbonly);
+        push_flag(&mut args, "--noprogressbar", self.noprogressbar);
+        push_flag(&mut args, "--noscriptlet", self.noscriptlet);
+        push_flag(&mut args, "--print", self.print);
+        push_value(&mut args, "--print-format", &self.print_format);
+        args
+    }
+}
+
+/// Options of operations that install packages (-S, -U)
+#[derive(Args, Debug, Default, Clone)]
+pub struct UpgradeOptions {
+    /// Install packages as non-explicitly installed
+    #[arg(long)]
+    pub asdeps: bool,
+    /// Install packages as explicitly installed
+    #[arg(long)]
+    pub asexplicit: bool,
+    /// Ignore a package upgrade (can be used more than once)
+    #[arg(long, value_name = "PKG")]
+    pub ignore: Vec<String>,
+    /// Ignore a group upgrade (can be used more than once)
+    #[arg(long, value_name = "GRP")]
+    pub ignoregroup: Vec<String>,
+    /// Do not reinstall up to date packages
+    #[arg(long)]
+    pub needed: bool,
+    /// Overwrite conflicting files (can be used more than once)
+    #[arg(long, value_name = "GLOB")]
+    pub overwrite: Vec<String>,
+}
+
+impl UpgradeOptions {
+    /// Packages given to `--ignore`, which also takes comma-separated lists
+    pub fn ignored(&self) -> Vec<String> {
+        self.ignore
+            .iter()
+            .flat_map(|list| list.split(','))
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        push_flag(&mut args, "--asdeps", self.asdeps);
+        push_flag(&mut args, "--asexplicit", self.asexplicit);
+        push_values(&mut args, "--ignore", &self.ignore);
+        push_values(&mut args, "--ignoregroup", &self.ignoregroup);
+        push_flag(&mut args, "--needed", self.needed);
+        push_values(&mut args, "--overwrite", &self.overwrite);
+        args
+    }
+}
+
+fn push_flag(args: &mut Vec<String>, flag: &str, set: bool) {
+    if set {
+        args.push(flag.to_string());
+    }
+}
+
+fn push_count(args: &mut Vec<String>, flag: &str, count: u8) {
+    for _ in 0..count {
+        args.push(flag.to_string());
+    }
+}
+
+fn push_value(args: &mut Vec<String>, flag: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        args.push(flag.to_string());
+        args.push(value.clone());
+    }
+}
+
+fn push_values(args: &mut Vec<String>, flag: &str, values: &[String]) {
+    for value in values {
+        args.push(flag.to_string());
+        args.push(value.clone());
+    }
 }
@@ -1,9 +1,12 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use directories::ProjectDirs;
 use fs2::FileExt;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(default)]
@@ -13,8 +16,140 @@ pub struct Config {
     pub clean_build: bool,
     pub show_news: bool,
     pub diff_viewer: bool,
+    pub upfront_review: bool,
+    pub scan_blocks_noconfirm: bool,
+    pub sudo_bin: Option<String>,
+    pub sudo_flags: Vec<String>,
+    pub build_user: Option<String>,
+    pub sudoloop: bool,
+    pub devel: bool,
+    pub vcs_pkgver: bool,
+    /// makepkg binary to build with
+    pub makepkg_bin: Option<String>,
+    /// makepkg.conf passed to makepkg with `--config`
+    pub makepkg_conf: Option<String>,
+    /// Extra flags passed to every makepkg build
+    pub makepkg_flags: Vec<String>,
+    /// Exported as `PKGDEST` to makepkg
+    pub pkgdest: Option<String>,
+    /// Exported as `SRCDEST` to makepkg
+    pub srcdest: Option<String>,
+    /// Remove orphaned dependencies after installing AUR packages
+    pub clean_orphans: bool,
+    /// Built packages kept per package name by `-Sc`
+    pub keep_artifacts: u64,
+    /// Directory of the clean chroot used by packages with `chroot = true`
+    pub chroot_dir: Option<String>,
+    /// Keyservers PGP keys are fetched from, tried in order
+    pub keyservers: Vec<String>,
+    /// Seconds after which a key lookup is given up
+    pub keyserver_timeout: u64,
+    /// Build without source signature checks when PGP keys cannot be fetched
+    pub allow_skippgpcheck: bool,
+    /// Import fetched PGP keys into the ax keyring without asking
+    pub auto_import_keys: bool,
+    /// Per-package overrides from `[packages.<pkgbase>]`
+    pub packages: BTreeMap<String, PackageConfig>,
     #[serde(skip)]
     pub no_confirm: bool,
+    /// Build AUR targets without installing them (`-Sw`)
+    #[serde(skip)]
+    pub build_only: bool,
+    /// Reset diverged or modified AUR clones without asking (`--reset-clones`)
+    #[serde(skip)]
+    pub reset_clones: bool,
+    /// Build packages that do not support the system architecture (`--ignorearch`)
+    #[serde(skip)]
+    pub ignore_arch: bool,
+    /// Layer each value was taken from, filled by `load_layered`
+    #[serde(skip)]
+    pub origins: BTreeMap<String, String>,
+}
+
+/// Build overrides for a single pkgbase
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageConfig {
+    /// Extra flags passed to makepkg, e.g. `--nocheck`
+    pub makepkg_flags: Vec<String>,
+    /// Extra environment variables for the build, e.g. `CFLAGS`
+    pub env: BTreeMap<String, String>,
+    /// Patch files applied to the clone after every clone/pull
+    pub patches: Vec<String>,
+    /// Build without showing the PKGBUILD and diffs for review
+    pub skip_review: bool,
+    /// Never upgrade or rebuild this package once installed
+    pub ignore: bool,
+    /// Build in a clean chroot with `makechrootpkg`
+    pub chroot: bool,
+    /// What to do when the clone has local changes or diverged from the AUR
+    pub recovery: Option<CloneRecovery>,
+    /// Build with `makepkg --ignorearch` even if `arch` excludes the system
+    pub ignore_arch: bool,
+}
+
+/// How to update an AUR clone that has local changes or diverged
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneRecovery {
+    /// Stash local changes, reset to the AUR and re-apply them
+    Stash,
+    /// Reset to the AUR, discarding local changes
+    Reset,
+    /// Keep the local version without updating
+    Keep,
+}
+
+impl CloneRecovery {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloneRecovery::Stash => "stash",
+            CloneRecovery::Reset => "reset",
+            CloneRecovery::Keep => "keep",
+        }
+    }
+}
+
+impl PackageConfig {
+    /// Human-readable list of the overrides in effect, for the plan output
+    pub fn summary(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        if !self.makepkg_flags.is_empty() {
+            out.push(format!("makepkg flags: {}", self.makepkg_flags.join(" ")));
+        }
+        if !self.env.is_empty() {
+            let vars: Vec<&str> = self.env.keys().map(|k| k.as_str()).collect();
+            out.push(format!("env: {}", vars.join(", ")));
+        }
+        if !self.patches.is_empty() {
+            out.push(format!("patches: {}", self.patches.join(", ")));
+        }
+        if self.skip_review {
+            out.push("review skipped".to_string());
+        }
+        if self.ignore {
+            out.push("ignored".to_string());
+        }
+        if self.chroot {
+            out.push("chroot build".to_string());
+        }
+        if let Some(recovery) = self.recovery {
+            out.push(format!("clone recovery: {}", recovery.as_str()));
+        }
+        if self.ignore_arch {
+            out.push("architecture ignored".to_string());
+        }
+        out
+    }
+
+    /// Patch paths. Relative ones from config files were already resolved
+    /// against the file's directory, others against the user config directory.
+    pub fn patch_paths(&self) -> Vec<PathBuf> {
+        let base = Config::path()
+            .and_then(|p| p.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+        self.patches.iter().map(|p| base.join(p)).collect()
+    }
 }
 
 impl Default for Config {
@@ -25,29 +160,298 @@ impl Default for Config {
             clean_build: false,
             show_news: true,
             diff_viewer: true,
+            upfront_review: true,
+            scan_blocks_noconfirm: false,
+            sudo_bin: None,
+            sudo_flags: Vec::new(),
+            build_user: None,
+            sudoloop: false,
+            devel: false,
+            vcs_pkgver: false,
+            makepkg_bin: None,
+            makepkg_conf: None,
+            makepkg_flags: Vec::new(),
+            pkgdest: None,
+            srcdest: None,
+            clean_orphans: false,
+            keep_artifacts: 1,
+            chroot_dir: None,
+            keyservers: vec![
+                "hkps://keyserver.ubuntu.com".to_string(),
+                "hkps://keys.openpgp.org".to_string(),
+            ],
+            keyserver_timeout: 30,
+            allow_skippgpcheck: false,
+            auto_import_keys: false,
+            packages: BTreeMap::new(),
             no_confirm: false,
+            build_only: false,
+            reset_clones: false,
+            ignore_arch: false,
+            origins: BTreeMap::new(),
         }
     }
 }
 
+type ConfigMap = serde_json::Map<String, serde_json::Value>;
+
+/// System-wide config, overridden by the user config
+const SYSTEM_CONFIG: &str = "/etc/ax/config.toml";
+/// Prefix of environment variable overrides, e.g. `AX_BUILD_DIR`
+const ENV_PREFIX: &str = "AX_";
+
 impl Config {
-    pub fn load() -> Result<Self> {
-        if let Some(proj_dirs) = ProjectDirs::from("com", "manpreet113", "ax") {
-            let config_dir = proj_dirs.config_dir();
-            let config_path = config_dir.join("config.toml");
+    /// Path of the user's `config.toml`
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("com", "manpreet113", "ax")
+            .map(|proj_dirs| proj_dirs.config_dir().join("config.toml"))
+    }
 
+    /// Merge the configuration layers in order: defaults, system config,
+    /// user config, `AX_*` environment variables and `--ax-config key=value`
+    /// overrides. The origin of every value is kept for `ax config list --origin`.
+    pub fn load_layered(overrides: &[String]) -> Result<Self> {
+        let mut files = vec![(PathBuf::from(SYSTEM_CONFIG), "system")];
+        files.extend(Self::path().map(|path| (path, "user")));
+        let env: HashMap<String, String> = std::env::vars()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect();
 
+        Self::merge_layers(&files, &env, overrides)
+    }
+
+    /// `load_layered` with the config `files` (path and layer name, lowest
+    /// priority first) and environment `env` given explicitly
+    fn merge_layers(
+        files: &[(PathBuf, &str)],
+        env: &HashMap<String, String>,
+        overrides: &[String],
+    ) -> Result<Self> {
+        let mut map = Self::default().to_map()?;
+        let mut origins: BTreeMap<String, String> = map
+            .keys()
+            .map(|key| (key.clone(), "default".to_string()))
+            .collect();
+
+        for (path, layer) in files {
+            if !path.exists() {
+                continue;
+            }
+            for (key, value) in Self::read_file(path)? {
+                origins.insert(key.clone(), format!("{} ({})", layer, path.display()));
+                // Tables like [packages.*] are merged entry by entry
+                match (value, map.get_mut(&key)) {
+                    (
+                        serde_json::Value::Object(entries),
+                        Some(serde_json::Value::Object(existing)),
+                    ) => existing.extend(entries),
+                    (value, _) => {
+                        map.insert(key, value);
+                    }
+                }
+            }
+            Self::from_map(map.clone())
+                .map_err(|e| anyhow::anyhow!("Invalid config at {:?}: {}", path, e))?;
+        }
+
+        let keys: Vec<String> = map.keys().cloned().collect();
+        for key in keys {
+            let var = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(raw) = env.get(&var) {
+                let value = Self::parse_value(&key, raw)
+                    .with_context(|| format!("Invalid value in {}", var))?;
+                map.insert(key.clone(), value);
+                origins.insert(key, format!("env ({})", var));
+            }
+        }
+
+        for entry in overrides {
+            let (key, raw) = entry.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Expected --ax-config key=value, got '{}'", entry)
+            })?;
+            let value = Self::parse_value(key, raw)?;
+            map.insert(key.to_string(), value);
+            origins.insert(key.to_string(), "command line (--ax-config)".to_string());
+        }
+
+        let mut config = Self::from_map(map)?;
+        config.origins = origins;
+        Ok(config)
+    }
+
+    /// Read the keys set in a config file, skipping unknown keys with a warning
+    fn read_file(path: &Path) -> Result<ConfigMap> {
+        let content = fs::read_to_string(path)?;
+        let table: toml::Table = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid config at {:?}:\n{}", path, e))?;
 
-            if config_path.exists() {
-                let content = fs::read_to_string(config_path)?;
-                let config: Config = toml::from_str(&content)?;
-                return Ok(config);
+        let known = Self::default().to_map()?;
+        let mut map = ConfigMap::new();
+        for (key, value) in table {
+            if !known.contains_key(&key) {
+                warn!("Ignoring unknown config key '{}' in {:?}", key, path);
+                continue;
             }
+            map.insert(key, serde_json::to_value(value)?);
+        }
+
+        // Relative patch paths belong to the directory of this file
+        if let Some(dir) = path.parent()
+            && let Some(serde_json::Value::Object(packages)) = map.get_mut("packages")
+        {
+            for package in packages.values_mut() {
+                if let Some(serde_json::Value::Array(patches)) = package.get_mut("patches") {
+                    for patch in patches {
+                        if let serde_json::Value::String(p) = patch
+                            && Path::new(p).is_relative()
+                        {
+                            *p = dir.join(&*p).to_string_lossy().to_string();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Validate a single config file on top of the defaults
+    pub fn validate_file(path: &Path) -> Result<()> {
+        let mut map = Self::default().to_map()?;
+        map.extend(Self::read_file(path)?);
+        Self::from_map(map).map_err(|e| anyhow::anyhow!("Invalid config at {:?}: {}", path, e))?;
+        Ok(())
+    }
+
+    fn to_map(&self) -> Result<ConfigMap> {
+        match serde_json::to_value(self)? {
+            serde_json::Value::Object(map) => Ok(map),
+            _ => unreachable!("Config always serializes to an object"),
+        }
+    }
+
+    fn from_map(map: ConfigMap) -> Result<Self> {
+        Ok(serde_json::from_value(serde_json::Value::Object(map))?)
+    }
+
+    /// All config keys with their current values, sorted by key
+    pub fn entries(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        Ok(self.to_map()?.into_iter().collect())
+    }
+
+    pub fn get_value(&self, key: &str) -> Result<serde_json::Value> {
+        self.to_map()?
+            .remove(key)
+            .ok_or_else(|| anyhow::anyhow!("Unknown config key: {}", key))
+    }
+
+    /// Where the value of `key` came from (default, system, user, env, command line)
+    pub fn origin(&self, key: &str) -> &str {
+        self.origins.get(key).map_or("default", |s| s.as_str())
+    }
+
+    /// Parse the string form of `key` (from the CLI or environment)
+    /// according to the key's type
+    pub fn parse_value(key: &str, value: &str) -> Result<serde_json::Value> {
+        let default = Self::default().get_value(key)?;
+
+        let parsed = match default {
+            serde_json::Value::Bool(_) => serde_json::Value::Bool(value.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid value for {}: expected true or false", key)
+            })?),
+            serde_json::Value::Number(_) => serde_json::Value::Number(
+                value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for {}: expected a number", key))?
+                    .into(),
+            ),
+            serde_json::Value::Array(_) => serde_json::Value::Array(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| serde_json::Value::String(v.to_string()))
+                    .collect(),
+            ),
+            serde_json::Value::Object(_) => {
+                anyhow::bail!("{} is a table, edit it with `ax config edit`", key)
+            }
+            // Options are null when unset; an empty value unsets them
+            serde_json::Value::Null if value.is_empty() => serde_json::Value::Null,
+            _ => serde_json::Value::String(value.to_string()),
+        };
+        Ok(parsed)
+    }
+
+    /// Set `key` in the user's config.toml, leaving all other keys untouched
+    pub fn set_user_value(key: &str, value: &str) -> Result<()> {
+        let parsed = Self::parse_value(key, value)?;
+        Self::edit_user_table(key, |table| {
+            match parsed {
+                serde_json::Value::Null => {
+                    table.remove(key);
+                }
+                parsed => {
+                    table.insert(key.to_string(), toml::Value::try_from(parsed)?);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Set `key` in the `[packages.<pkgbase>]` table of the user config
+    pub fn set_package_value(pkgbase: &str, key: &str, value: toml::Value) -> Result<()> {
+        Self::edit_user_table(&format!("packages.{}.{}", pkgbase, key), |table| {
+            let packages = table
+                .entry("packages")
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow::anyhow!("`packages` is not a table"))?;
+            packages
+                .entry(pkgbase)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow::anyhow!("`packages.{}` is not a table", pkgbase))?
+                .insert(key.to_string(), value);
+            Ok(())
+        })
+    }
+
+    /// Apply `edit` to the user config file's table, validate the result and
+    /// write it back. Only the edited keys change.
+    fn edit_user_table(key: &str, edit: impl FnOnce(&mut toml::Table) -> Result<()>) -> Result<()> {
+        let config_path =
+            Self::path().ok_or_else(|| anyhow::anyhow!("Could not determine config directory"))?;
+
+        let mut table: toml::Table = if config_path.exists() {
+            toml::from_str(&fs::read_to_string(&config_path)?)
+                .map_err(|e| anyhow::anyhow!("Invalid config at {:?}:\n{}", config_path, e))?
+        } else {
+            toml::Table::new()
+        };
+
+        edit(&mut table)?;
+
+        // Validate the result before writing it
+        let mut map = Self::default().to_map()?;
+        for (k, v) in &table {
+            map.insert(k.clone(), serde_json::to_value(v)?);
+        }
+        Self::from_map(map).map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?;
+
+        write_locked(&config_path, &toml::to_string_pretty(&table)?)
+    }
+
+    /// Render a config value for display (`raw` prints strings unquoted)
+    pub fn format_value(value: &serde_json::Value, raw: bool) -> String {
+        match value {
+            serde_json::Value::Null => "(unset)".to_string(),
+            serde_json::Value::String(s) if raw => s.clone(),
+            other => toml::Value::try_from(other)
+                .map(|v| v.to_string())
+                .unwrap_or_else(|_| other.to_string()),
         }
-        Ok(Self::default())
     }
 
-    // TODO: Implement config modification command
     pub fn get_default_cache_dir() -> std::path::PathBuf {
         if let Some(proj_dirs) = ProjectDirs::from("com", "manpreet113", "ax") {
             proj_dirs.cache_dir().to_path_buf()
@@ -59,6 +463,17 @@ impl Config {
         }
     }
 
+    pub fn get_data_dir() -> std::path::PathBuf {
+        if let Some(proj_dirs) = ProjectDirs::from("com", "manpreet113", "ax") {
+            proj_dirs.data_dir().to_path_buf()
+        } else {
+            std::env::var("HOME")
+                .ok()
+                .map(|h| std::path::PathBuf::from(format!("{}/.local/share/ax", h)))
+                .unwrap_or_else(|| std::path::PathBuf::from(".local/share/ax"))
+        }
+    }
+
     pub fn get_cache_dir(&self) -> std::path::PathBuf {
         if let Some(ref dir) = self.build_dir {
             std::path::PathBuf::from(dir)
@@ -67,22 +482,144 @@ impl Config {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn save(&self) -> Result<()> {
-        if let Some(proj_dirs) = ProjectDirs::from("com", "manpreet113", "ax") {
-            let config_dir = proj_dirs.config_dir();
-            fs::create_dir_all(config_dir)?;
+    /// Overrides for `pkgbase`, or the defaults if it has no `[packages]` entry
+    pub fn package(&self, pkgbase: &str) -> PackageConfig {
+        self.packages.get(pkgbase).cloned().unwrap_or_default()
+    }
 
-            let config_path = config_dir.join("config.toml");
-            let content = toml::to_string_pretty(self)?;
+    /// Editor for reviews: config > $EDITOR > nano
+    pub fn get_editor(&self) -> String {
+        self.editor
+            .clone()
+            .or_else(|| std::env::var("EDITOR").ok())
+            .unwrap_or_else(|| "nano".to_string())
+    }
+}
 
-            // Use file locking to prevent corruption from concurrent saves
-            let mut file = File::create(&config_path)?;
-            file.lock_exclusive()?;
-            file.write_all(content.as_bytes())?;
-            file.flush()?;
-            file.unlock()?;
-        }
-        Ok(())
+/// Write `content` to `path` under an exclusive lock, creating parent directories
+pub fn write_locked(path: &Path, content: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Use file locking to prevent corruption from concurrent saves. The file
+    // is only truncated once the lock is held, so readers never see it empty
+    // because of a writer still waiting for the lock.
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    file.lock_exclusive()?;
+    file.set_len(0)?;
+    file.write_all(content.as_bytes())?;
+    file.flush()?;
+    file.unlock()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_value_typed() {
+        assert_eq!(
+            Config::parse_value("clean_build", "true").unwrap(),
+            serde_json::json!(true)
+        );
+        assert!(Config::parse_value("clean_build", "yes please").is_err());
+
+        assert_eq!(
+            Config::parse_value("editor", "nvim").unwrap(),
+            serde_json::json!("nvim")
+        );
+        assert!(Config::parse_value("editor", "").unwrap().is_null());
+
+        assert_eq!(
+            Config::parse_value("sudo_flags", "-E, --preserve-env").unwrap(),
+            serde_json::json!(["-E", "--preserve-env"])
+        );
+
+        assert!(Config::parse_value("no_such_key", "1").is_err());
+    }
+
+    #[test]
+    fn test_cli_overrides_win() {
+        let dir = crate::tempdir::TempDir::new("ax-config-layers").unwrap();
+        let system = dir.path().join("system.toml");
+        let user = dir.path().join("user.toml");
+        fs::write(
+            &system,
+            "clean_build = true\nkeep_artifacts = 3\ndevel = true\n",
+        )
+        .unwrap();
+        fs::write(&user, "clean_build = false\nkeep_artifacts = 5\n").unwrap();
+        let files = [(system, "system"), (user.clone(), "user")];
+        let env = HashMap::from([("AX_KEEP_ARTIFACTS".to_string(), "7".to_string())]);
+
+        let config = Config::merge_layers(&files, &env, &[]).unwrap();
+        assert!(config.devel);
+        assert!(!config.clean_build);
+        assert_eq!(
+            config.origin("clean_build"),
+            format!("user ({})", user.display())
+        );
+        assert_eq!(config.keep_artifacts, 7);
+        assert_eq!(config.origin("keep_artifacts"), "env (AX_KEEP_ARTIFACTS)");
+
+        let overrides = [
+            "clean_build=true".to_string(),
+            "keep_artifacts=9".to_string(),
+        ];
+        let config = Config::merge_layers(&files, &env, &overrides).unwrap();
+        assert!(config.clean_build);
+        assert_eq!(config.keep_artifacts, 9);
+        assert_eq!(config.origin("clean_build"), "command line (--ax-config)");
+
+        assert!(Config::merge_layers(&[], &HashMap::new(), &["clean_build".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_package_overrides() {
+        let config: Config = toml::from_str(
+            r#"
+            [packages.foo]
+            makepkg_flags = ["--nocheck"]
+            env = { CFLAGS = "-O3" }
+            skip_review = true
+            "#,
+        )
+        .unwrap();
+
+        let foo = config.package("foo");
+        assert_eq!(foo.makepkg_flags, ["--nocheck"]);
+        assert_eq!(foo.env["CFLAGS"], "-O3");
+        assert_eq!(
+            foo.summary(),
+            ["makepkg flags: --nocheck", "env: CFLAGS", "review skipped"]
+        );
+        assert_eq!(config.package("bar"), PackageConfig::default());
+
+        assert!(toml::from_str::<Config>("[packages.foo]\nmakepkg_flag = []").is_err());
+    }
+
+    #[test]
+    fn test_patch_paths_relative_to_file() {
+        let tmp = crate::tempdir::TempDir::new("ax-config-patches").unwrap();
+        let dir = tmp.path();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "[packages.foo]\npatches = [\"fix.patch\", \"/srv/abs.patch\"]\n",
+        )
+        .unwrap();
+
+        let config = Config::from_map(Config::read_file(&path).unwrap()).unwrap();
+        assert_eq!(
+            config.package("foo").patch_paths(),
+            [dir.join("fix.patch"), PathBuf::from("/srv/abs.patch")]
+        );
     }
 }
@@ -0,0 +1,347 @@
+use crate::arch::ArchDB;
+use crate::config::Config;
+use crate::pkgname::PkgName;
+use anyhow::{Context, Result};
+use colored::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// An upstream VCS source of a package and the commit it was last built from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VcsSource {
+    pub url: String,
+    pub branch: Option<String>,
+    pub commit: String,
+}
+
+/// Devel database (`devel.json` in the data dir), keyed by pkgbase
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DevelDb {
+    #[serde(flatten)]
+    packages: BTreeMap<String, Vec<VcsSource>>,
+}
+
+impl DevelDb {
+    fn path() -> PathBuf {
+        Config::get_data_dir().join("devel.json")
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse devel database at {:?}", path))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        crate::config::write_locked(&Self::path(), &serde_json::to_string_pretty(self)?)
+    }
+
+    /// Record the commits the VCS sources of the package cloned at
+    /// `cache_path` were built from, as found in makepkg's checkouts, or
+    /// the current upstream heads if there are none (e.g. for `--gendb`).
+    /// Packages without VCS sources are dropped.
+    pub fn record(&mut self, pkgbase: &str, cache_path: &Path, config: &Config) -> Result<()> {
+        let metadata = crate::parser::parse_srcinfo(cache_path)?;
+
+        let mut sources = Vec::new();
+        for source in &metadata.sources {
+            let Some((url, branch)) = parse_vcs_source(source) else {
+                continue;
+            };
+            let built = built_commit(cache_path, config, source, branch.as_deref());
+            match built.or_else(|| ls_remote(&url, branch.as_deref())) {
+                Some(commit) => sources.push(VcsSource {
+                    url,
+                    branch,
+                    commit,
+                }),
+                None => debug!("git ls-remote failed for {}", url),
+            }
+        }
+
+        if sources.is_empty() {
+            self.packages.remove(pkgbase);
+        } else {
+            self.packages.insert(pkgbase.to_string(), sources);
+        }
+        Ok(())
+    }
+}
+
+/// Extract the git URL and branch from a PKGBUILD `source=` entry.
+/// Returns `None` for non-git sources and sources pinned to a commit or tag.
+pub fn parse_vcs_source(source: &str) -> Option<(String, Option<String>)> {
+    let source = source.split_once("::").map_or(source, |(_, url)| url);
+
+    let url = if let Some(url) = source.strip_prefix("git+") {
+        url
+    } else if source.starts_with("git://") {
+        source
+    } else {
+        return None;
+    };
+
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    // Drop the `?signed` query makepkg uses for signature verification
+    let url = url.split_once('?').map_or(url, |(url, _)| url);
+
+    let branch = match fragment.and_then(|f| f.split_once('=')) {
+        Some(("branch", branch)) => Some(branch.to_string()),
+        Some(_) => return None,
+        None => None,
+    };
+
+    Some((url.to_string(), branch))
+}
+
+/// Directory makepkg clones a VCS `source=` entry into: the `name::`
+/// prefix, or the last URL path segment without `.git`
+fn source_dir_name(source: &str) -> String {
+    if let Some((name, _)) = source.split_once("::") {
+        return name.to_string();
+    }
+    let url = source.split(['#', '?']).next().unwrap_or(source);
+    let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+    name.strip_suffix(".git").unwrap_or(name).to_string()
+}
+
+/// Commit of `source` the last build used: HEAD of the checkout in `src/`,
+/// or the branch (or HEAD) of makepkg's bare clone in `SRCDEST` or the
+/// clone for chroot builds, which check out elsewhere
+fn built_commit(
+    cache_path: &Path,
+    config: &Config,
+    source: &str,
+    branch: Option<&str>,
+) -> Option<String> {
+    let name = source_dir_name(source);
+    let commit_of = |repo: git2::Repository, spec: &str| {
+        let commit = repo.revparse_single(spec).ok()?.peel_to_commit().ok()?;
+        Some(commit.id().to_string())
+    };
+
+    if let Ok(repo) = git2::Repository::open(cache_path.join("src").join(&name)) {
+        return commit_of(repo, "HEAD");
+    }
+    let spec = branch
+        .map(|b| format!("refs/heads/{}", b))
+        .unwrap_or_else(|| "HEAD".to_string());
+    let srcdest = config
+        .srcdest
+        .as_deref()
+        .map(Path::new)
+        .unwrap_or(cache_path);
+    git2::Repository::open_bare(srcdest.join(&name))
+        .ok()
+        .and_then(|repo| commit_of(repo, &spec))
+}
+
+/// Seconds `git ls-remote` may take before it is killed
+const LS_REMOTE_TIMEOUT: u64 = 30;
+
+/// Remotes queried at the same time by `check_updates`
+const LS_REMOTE_JOBS: usize = 8;
+
+/// Ask the remote for the commit its branch (or HEAD) currently points at
+fn ls_remote(url: &str, branch: Option<&str>) -> Option<String> {
+    let refname = branch
+        .map(|b| format!("refs/heads/{}", b))
+        .unwrap_or_else(|| "HEAD".to_string());
+
+    let mut child = Command::new("git")
+        .args(["ls-remote", url, &refname])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        // Give up on stalled HTTP transfers; the kill below covers the rest
+        .env("GIT_HTTP_LOW_SPEED_LIMIT", "1000")
+        .env("GIT_HTTP_LOW_SPEED_TIME", LS_REMOTE_TIMEOUT.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if start.elapsed() < Duration::from_secs(LS_REMOTE_TIMEOUT) => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            _ => {
+                debug!("git ls-remote {} timed out", url);
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    // A single ref fits into the pipe, so reading after exit cannot block
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string())
+}
+
+/// Check the upstream heads of all installed devel packages and return the
+/// pkgbases whose recorded commit is out of date.
+pub fn check_updates(db: &DevelDb, installed_bases: &HashSet<String>) -> Vec<String> {
+    let tracked: Vec<_> = db
+        .packages
+        .iter()
+        .filter(|(pkgbase, _)| installed_bases.contains(*pkgbase))
+        .collect();
+
+    // ls-remote is network bound, so a few workers query packages concurrently
+    let next = AtomicUsize::new(0);
+    let mut outdated: Vec<String> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..LS_REMOTE_JOBS.min(tracked.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut outdated = Vec::new();
+                    while let Some((pkgbase, sources)) =
+                        tracked.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let changed = sources.iter().any(|source| {
+                            match ls_remote(&source.url, source.branch.as_deref()) {
+                                Some(head) => head != source.commit,
+                                None => {
+                                    debug!("git ls-remote failed for {}", source.url);
+                                    false
+                                }
+                            }
+                        });
+                        if changed {
+                            outdated.push((*pkgbase).clone());
+                        }
+                    }
+                    outdated
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .filter_map(|h| h.join().ok())
+            .flatten()
+            .collect()
+    });
+
+    outdated.sort();
+    outdated
+}
+
+/// Seed the devel database from all installed foreign packages
+pub fn gendb(config: &Config) -> Result<()> {
+    println!("{}", ":: Generating devel database...".blue().bold());
+
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    let cache_base = config.get_cache_dir();
+    let mut db = DevelDb::load()?;
+
+    for pkgbase in arch_db.get_foreign_pkgbases() {
+        // Used as a directory name and in the clone URL
+        let pkgbase = match PkgName::new(&pkgbase) {
+            Ok(pkgbase) => pkgbase,
+            Err(e) => {
+                eprintln!("{} Skipping {}: {:#}", "!!".yellow(), pkgbase, e);
+                continue;
+            }
+        };
+        let cache_path = cache_base.join(&pkgbase);
+        if !cache_path.exists() {
+            let aur_url = format!("https://aur.archlinux.org/{}.git", pkgbase);
+            if let Err(e) = crate::git_ops::clone_repo(&aur_url, &cache_path) {
+                eprintln!("{} Skipping {}: {:#}", "!!".yellow(), pkgbase, e);
+                continue;
+            }
+        }
+
+        if let Err(e) = db.record(&pkgbase, &cache_path, config) {
+            eprintln!("{} Skipping {}: {:#}", "!!".yellow(), pkgbase, e);
+            continue;
+        }
+        if let Some(sources) = db.packages.get(pkgbase.as_str()) {
+            println!(
+                "   {} {} ({} source(s))",
+                "✓".green(),
+                pkgbase,
+                sources.len()
+            );
+        }
+    }
+
+    db.save()?;
+    println!("{}", ":: Devel database generated.".green());
+    Ok(())
+}
+
+/// Record the VCS heads of a freshly built and installed pkgbase
+pub fn record_build(pkgbase: &PkgName, config: &Config) -> Result<()> {
+    let mut db = DevelDb::load()?;
+    db.record(pkgbase, &config.get_cache_dir().join(pkgbase), config)?;
+    db.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vcs_source() {
+        assert_eq!(
+            parse_vcs_source("git+https://github.com/foo/bar.git"),
+            Some(("https://github.com/foo/bar.git".to_string(), None))
+        );
+        assert_eq!(
+            parse_vcs_source("bar::git+https://github.com/foo/bar.git#branch=dev"),
+            Some((
+                "https://github.com/foo/bar.git".to_string(),
+                Some("dev".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_vcs_source("git+https://github.com/foo/bar.git?signed"),
+            Some(("https://github.com/foo/bar.git".to_string(), None))
+        );
+        assert_eq!(
+            parse_vcs_source("git://example.org/bar.git"),
+            Some(("git://example.org/bar.git".to_string(), None))
+        );
+        assert!(parse_vcs_source("git+https://github.com/foo/bar.git#tag=v1.0").is_none());
+        assert!(parse_vcs_source("git+https://github.com/foo/bar.git#commit=abc").is_none());
+        assert!(parse_vcs_source("https://example.org/bar-1.0.tar.gz").is_none());
+    }
+
+    #[test]
+    fn test_source_dir_name() {
+        assert_eq!(source_dir_name("git+https://github.com/foo/bar.git"), "bar");
+        assert_eq!(
+            source_dir_name("baz::git+https://github.com/foo/bar.git"),
+            "baz"
+        );
+        assert_eq!(
+            source_dir_name("git+https://example.org/foo/bar/#branch=dev"),
+            "bar"
+        );
+        assert_eq!(source_dir_name("git://example.org/bar.git?signed"), "bar");
+    }
+}
@@ -0,0 +1,281 @@
+use crate::api;
+use crate::arch::ArchDB;
+use crate::builder;
+use crate::clean;
+use crate::config::Config;
+use crate::git_ops;
+use crate::git_ops::LogEntry;
+use crate::history::{self, HistoryEntry};
+use crate::interactive;
+use crate::pkgname::PkgName;
+use crate::privilege;
+use crate::tempdir::TempDir;
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Number of AUR commits offered
+const LOG_LIMIT: usize = 30;
+
+enum Choice {
+    /// A package built earlier, reinstalled as is
+    Cached { version: String, path: PathBuf },
+    /// A commit of the AUR repo, rebuilt
+    Commit { version: String, entry: LogEntry },
+}
+
+/// Downgrade the AUR package `target` to a cached build or an older commit
+/// of its AUR repo, and offer to hold it there
+pub async fn downgrade(target: &str, config: &Config) -> Result<()> {
+    let target = PkgName::new(target)?;
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    if arch_db.exists_in_repo(&target) {
+        anyhow::bail!(
+            "{} is a repo package. Downgrade it from pacman's cache with pacman -U.",
+            target
+        );
+    }
+
+    let pkgbase = match arch_db.get_installed_pkgbase(&target) {
+        Some(pkgbase) => PkgName::new(&pkgbase)?,
+        None => {
+            let infos = api::get_info(std::slice::from_ref(&target)).await?;
+            let pkg = infos
+                .first()
+                .with_context(|| format!("{} was not found in the AUR", target))?;
+            pkg.pkgbase()?
+        }
+    };
+    let installed = arch_db.get_installed_version(&target);
+
+    let cache_path = config.get_cache_dir().join(&pkgbase);
+    if !cache_path.exists() {
+        let url = format!("https://aur.archlinux.org/{}.git", pkgbase);
+        git_ops::clone_repo(&url, &cache_path)?;
+    }
+    git_ops::unshallow(&cache_path)?;
+
+    let mut choices: Vec<Choice> = cached_builds(config, &cache_path, &target)
+        .into_iter()
+        .map(|(version, path)| Choice::Cached { version, path })
+        .collect();
+    let cached_count = choices.len();
+    choices.extend(
+        git_ops::log(&cache_path, LOG_LIMIT)?
+            .into_iter()
+            .filter_map(|entry| {
+                Some(Choice::Commit {
+                    version: entry.version.clone()?,
+                    entry,
+                })
+            }),
+    );
+    if choices.is_empty() {
+        anyhow::bail!("No cached builds or AUR commits found for {}", target);
+    }
+
+    println!(
+        "\n{} {} ({})",
+        ":: Versions of".blue().bold(),
+        target.bold(),
+        installed.as_deref().unwrap_or("not installed")
+    );
+    for (i, choice) in choices.iter().enumerate() {
+        if i == 0 && cached_count > 0 {
+            println!("{}", "   Cached builds:".bold());
+        } else if i == cached_count {
+            println!("{}", "   AUR commits:".bold());
+        }
+        let (version, detail) = match choice {
+            Choice::Cached { version, path } => (version, path.display().to_string()),
+            Choice::Commit { version, entry } => (
+                version,
+                format!(
+                    "{} {} {}",
+                    entry.id.chars().take(8).collect::<String>(),
+                    history::format_timestamp(entry.time.max(0) as u64),
+                    entry.summary
+                ),
+            ),
+        };
+        let marker = if installed.as_ref() == Some(version) {
+            " [installed]".green().to_string()
+        } else {
+            String::new()
+        };
+        println!(
+            "   {:>2} {} {}{}",
+            i + 1,
+            version.cyan(),
+            detail.dimmed(),
+            marker
+        );
+    }
+
+    if config.no_confirm {
+        anyhow::bail!("--downgrade needs a terminal to pick a version");
+    }
+    let Some(idx) = interactive::prompt_choice("Version to install", choices.len())? else {
+        println!(":: Nothing selected.");
+        return Ok(());
+    };
+
+    let mut run = HistoryEntry::new();
+    run.build_order = vec![pkgbase.to_string()];
+    let result = match &choices[idx] {
+        Choice::Cached { version, path } => {
+            // Installed from a private copy checked against the recorded hash
+            let staging = TempDir::new("ax-downgrade")?;
+            let package = history::stage_verified(
+                &history::load()?,
+                vec![path.clone()],
+                &target,
+                version,
+                staging.path(),
+            )
+            .with_context(|| {
+                format!(
+                    "{} does not match the checksum recorded when it was built",
+                    path.display()
+                )
+            })?;
+            if !package.verified
+                && !interactive::prompt_default_no(&format!(
+                    "{} has no recorded checksum. Install it anyway?",
+                    path.display()
+                ))?
+            {
+                return Ok(());
+            }
+
+            let paths = vec![path.clone()];
+            run.record_build(&pkgbase, &cache_path, &paths, &arch_db, false);
+            install(config, std::slice::from_ref(&package.staged))
+                .map(|()| run.mark_installed(&paths))
+        }
+        Choice::Commit { entry, .. } => {
+            rebuild_at(config, &pkgbase, &cache_path, &entry.id).and_then(|paths| {
+                // Split packages that are not installed stay out of it
+                let paths: Vec<PathBuf> = paths
+                    .into_iter()
+                    .filter(|path| {
+                        let name = path
+                            .file_name()
+                            .and_then(|f| clean::artifact_pkgname(&f.to_string_lossy()));
+                        name.is_some_and(|name| {
+                            target == name || arch_db.get_installed_version(&name).is_some()
+                        })
+                    })
+                    .collect();
+                run.record_build(&pkgbase, &cache_path, &paths, &arch_db, false);
+                install(config, &paths).map(|()| run.mark_installed(&paths))
+            })
+        }
+    };
+    run.finish(&result);
+    result?;
+
+    if interactive::prompt_default_no(&format!(
+        "Hold {} at this version (packages.{}.ignore)?",
+        pkgbase, pkgbase
+    ))? {
+        Config::set_package_value(&pkgbase, "ignore", toml::Value::Boolean(true))?;
+        println!(
+            ":: {} will be skipped by upgrades until `ignore` is removed from [packages.{}].",
+            pkgbase.cyan(),
+            pkgbase
+        );
+    }
+    Ok(())
+}
+
+/// Built packages of `pkgname` in the clone and `pkgdest`, newest version
+/// first
+fn cached_builds(config: &Config, cache_path: &Path, pkgname: &str) -> Vec<(String, PathBuf)> {
+    let mut dirs = vec![cache_path.to_path_buf()];
+    if let Some(pkgdest) = &config.pkgdest {
+        dirs.push(PathBuf::from(pkgdest));
+    }
+
+    let mut builds: Vec<(String, PathBuf)> = dirs
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if clean::artifact_pkgname(&file_name)? != pkgname {
+                return None;
+            }
+            Some((clean::artifact_version(&file_name)?, entry.path()))
+        })
+        .collect();
+    builds.sort_by(|a, b| ArchDB::vercmp(&b.0, &a.0));
+    builds.dedup_by(|a, b| a.0 == b.0);
+    builds
+}
+
+/// Build the clone at `cache_path` as of commit `id`, then check out the
+/// branch again so later pulls keep working. The built commit stays the
+/// reviewed one, so the next upgrade shows the diff from it.
+fn rebuild_at(
+    config: &Config,
+    pkgbase: &PkgName,
+    cache_path: &Path,
+    id: &str,
+) -> Result<Vec<PathBuf>> {
+    let branch = git_ops::head_ref(cache_path)
+        .with_context(|| format!("The clone of {} is not on a branch", pkgbase))?;
+    let patches = config.package(pkgbase).patch_paths();
+
+    git_ops::checkout(cache_path, id)?;
+    let result = git_ops::apply_patches(cache_path, &patches)
+        .and_then(|()| builder::build_package(pkgbase, config, true, true, &[]));
+
+    git_ops::checkout(cache_path, &branch)?;
+    git_ops::apply_patches(cache_path, &patches)?;
+    if result.is_ok() {
+        git_ops::mark_reviewed_at(cache_path, id)?;
+    }
+    result
+}
+
+fn install(config: &Config, paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() {
+        anyhow::bail!("No packages to install");
+    }
+    let status = privilege::pacman(config, true)?
+        .arg("-U")
+        .args(paths)
+        .status()
+        .context("Failed to execute pacman -U")?;
+    if !status.success() {
+        anyhow::bail!("Failed to install the selected version");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_builds() {
+        let tmp = crate::tempdir::TempDir::new("ax-downgrade-test").unwrap();
+        let dir = tmp.path();
+        for file in [
+            "foo-1.9-1-x86_64.pkg.tar.zst",
+            "foo-1.10-1-x86_64.pkg.tar.zst",
+            "foo-1.10-1-x86_64.pkg.tar.zst.sig",
+            "foo-docs-1.10-1-any.pkg.tar.zst",
+        ] {
+            fs::write(dir.join(file), "x").unwrap();
+        }
+
+        let versions: Vec<String> = cached_builds(&Config::default(), dir, "foo")
+            .into_iter()
+            .map(|(version, _)| version)
+            .collect();
+        assert_eq!(versions, ["1.10-1", "1.9-1"]);
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const SHT_DYNAMIC: u32 = 6;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+
+/// Minimal view of an ELF file, just enough to read the section headers
+struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 0x34 || data[..4] != ELF_MAGIC {
+            return None;
+        }
+        let is_64 = match data[4] {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        let little_endian = match data[5] {
+            1 => true,
+            2 => false,
+            _ => return None,
+        };
+        Some(Self {
+            data,
+            is_64,
+            little_endian,
+        })
+    }
+
+    fn read_u16(&self, off: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(off..off + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_u32(&self, off: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(off..off + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_u64(&self, off: usize) -> Option<u64> {
+        let bytes: [u8; 8] = self.data.get(off..off + 8)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        })
+    }
+
+    /// Read a native word (32 or 64 bit depending on the ELF class)
+    fn read_word(&self, off: usize) -> Option<u64> {
+        if self.is_64 {
+            self.read_u64(off)
+        } else {
+            self.read_u32(off).map(u64::from)
+        }
+    }
+
+    /// Returns (type, offset, size, link) for section `idx`
+    fn section(&self, idx: usize) -> Option<(u32, usize, usize, usize)> {
+        let (shoff, shentsize) = if self.is_64 {
+            (self.read_u64(0x28)? as usize, self.read_u16(0x3a)? as usize)
+        } else {
+            (self.read_u32(0x20)? as usize, self.read_u16(0x2e)? as usize)
+        };
+        let base = shoff.checked_add(idx.checked_mul(shentsize)?)?;
+
+        let sh_type = self.read_u32(base + 4)?;
+        let (offset, size, link) = if self.is_64 {
+            (
+                self.read_u64(base + 0x18)? as usize,
+                self.read_u64(base + 0x20)? as usize,
+                self.read_u32(base + 0x28)? as usize,
+            )
+        } else {
+            (
+                self.read_u32(base + 0x10)? as usize,
+                self.read_u32(base + 0x14)? as usize,
+                self.read_u32(base + 0x18)? as usize,
+            )
+        };
+        Some((sh_type, offset, size, link))
+    }
+
+    fn section_count(&self) -> Option<usize> {
+        let off = if self.is_64 { 0x3c } else { 0x30 };
+        self.read_u16(off).map(|n| n as usize)
+    }
+
+    fn read_cstr(&self, off: usize) -> Option<String> {
+        let bytes = self.data.get(off..)?;
+        let end = bytes.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).to_string())
+    }
+
+    fn needed(&self) -> Option<Vec<String>> {
+        let mut needed = Vec::new();
+
+        for idx in 0..self.section_count()? {
+            let (sh_type, offset, size, link) = self.section(idx)?;
+            if sh_type != SHT_DYNAMIC {
+                continue;
+            }
+
+            let (_, strtab_off, _, _) = self.section(link)?;
+            let entsize = if self.is_64 { 16 } else { 8 };
+            let word = entsize / 2;
+
+            let mut pos = offset;
+            while pos + entsize <= offset.checked_add(size)? {
+                let tag = self.read_word(pos)?;
+                let val = self.read_word(pos + word)? as usize;
+                if tag == DT_NULL {
+                    break;
+                }
+                if tag == DT_NEEDED {
+                    needed.push(self.read_cstr(strtab_off.checked_add(val)?)?);
+                }
+                pos += entsize;
+            }
+        }
+
+        Some(needed)
+    }
+}
+
+/// Returns true if the file at `path` starts with the ELF magic bytes.
+pub fn is_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && magic == ELF_MAGIC
+}
+
+/// Read the `DT_NEEDED` entries (linked sonames) of an ELF file.
+/// Returns `Ok(None)` if the file is not a (well-formed) ELF object.
+pub fn needed_libraries(path: &Path) -> Result<Option<Vec<String>>> {
+    if !is_elf(path) {
+        return Ok(None);
+    }
+    let data = std::fs::read(path)?;
+    Ok(Elf::parse(&data).and_then(|elf| elf.needed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needed_libraries_of_test_binary() {
+        let exe = std::env::current_exe().unwrap();
+        let needed = needed_libraries(&exe).unwrap().unwrap();
+        assert!(needed.iter().any(|lib| lib.starts_with("libc.so")));
+    }
+
+    #[test]
+    fn test_non_elf_file() {
+        let tmp = crate::tempdir::TempDir::new("ax-elf-test").unwrap();
+        let path = tmp.path().join("script");
+        std::fs::write(&path, "#!/bin/sh\necho hi\n").unwrap();
+        assert!(needed_libraries(&path).unwrap().is_none());
+    }
+}
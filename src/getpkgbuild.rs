@@ -0,0 +1,142 @@
+use crate::api;
+use crate::arch::ArchDB;
+use crate::git_ops;
+use crate::pkgname::PkgName;
+use anyhow::{Context, Result};
+use colored::*;
+use std::path::Path;
+
+/// Packaging repos of the official repositories
+const REPO_GIT_BASE: &str = "https://gitlab.archlinux.org/archlinux/packaging/packages";
+
+/// Clone the packaging repo of each target (repo or AUR) into `dest/<pkgbase>`
+pub async fn get_pkgbuilds(targets: &[String], dest: &Path) -> Result<()> {
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+
+    let mut clones = Vec::new();
+    let mut aur_targets = Vec::new();
+    for target in targets {
+        match arch_db.get_repo_pkgbase(target) {
+            Some(pkgbase) => {
+                let url = repo_url(&pkgbase);
+                clones.push((pkgbase, url));
+            }
+            None => aur_targets.push(PkgName::new(target)?),
+        }
+    }
+
+    let infos = api::get_info(&aur_targets).await?;
+    let mut missing = false;
+    for target in &aur_targets {
+        match infos.iter().find(|pkg| pkg.name == target.as_str()) {
+            Some(pkg) => {
+                let pkgbase = match pkg.pkgbase() {
+                    Ok(pkgbase) => pkgbase.to_string(),
+                    Err(e) => {
+                        eprintln!("{} Skipping AUR result: {:#}", "!!".yellow(), e);
+                        missing = true;
+                        continue;
+                    }
+                };
+                let url = aur_url(&pkgbase);
+                clones.push((pkgbase, url));
+            }
+            None => {
+                eprintln!("{} {} was not found", "!!".red().bold(), target);
+                missing = true;
+            }
+        }
+    }
+
+    clones.sort();
+    clones.dedup();
+    for (pkgbase, url) in clones {
+        let path = dest.join(&pkgbase);
+        if path.exists() {
+            println!(
+                "{} {} already exists, skipping",
+                ":: Skipping".yellow(),
+                path.display()
+            );
+            continue;
+        }
+        git_ops::clone_repo(&url, &path)?;
+    }
+
+    if missing {
+        anyhow::bail!("Some targets could not be found");
+    }
+    Ok(())
+}
+
+/// Whether `url` is where `-G` clones `pkgbase` from, the AUR or the
+/// packaging repos of the official repos
+pub fn is_packaging_url(pkgbase: &str, url: &str) -> bool {
+    url == aur_url(pkgbase) || url == repo_url(pkgbase)
+}
+
+fn aur_url(pkgbase: &str) -> String {
+    format!("https://aur.archlinux.org/{}.git", pkgbase)
+}
+
+fn repo_url(pkgbase: &str) -> String {
+    format!("{}/{}.git", REPO_GIT_BASE, gitlab_project_name(pkgbase))
+}
+
+/// GitLab project name of a pkgbase, following the rules of `pkgctl repo clone`
+fn gitlab_project_name(pkgbase: &str) -> String {
+    if pkgbase == "tree" {
+        return "unix-tree".to_string();
+    }
+
+    // "foo+bar" -> "foo-bar", remaining "+" -> "plus"
+    let chars: Vec<char> = pkgbase.chars().collect();
+    let mut name = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        match c {
+            '+' if i > 0
+                && chars[i - 1].is_ascii_alphanumeric()
+                && chars.get(i + 1).is_some_and(|n| n.is_ascii_alphabetic()) =>
+            {
+                name.push('-')
+            }
+            '+' => name.push_str("plus"),
+            c if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') => name.push(*c),
+            _ => name.push('-'),
+        }
+    }
+
+    // Collapse runs of "_" and "-" into a single "-"
+    let mut collapsed = String::new();
+    let mut run = String::new();
+    for c in name.chars().chain(std::iter::once('\0')) {
+        if matches!(c, '_' | '-') {
+            run.push(c);
+            continue;
+        }
+        if run.len() > 1 {
+            collapsed.push('-');
+        } else {
+            collapsed.push_str(&run);
+        }
+        run.clear();
+        if c != '\0' {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gitlab_project_name() {
+        assert_eq!(gitlab_project_name("linux"), "linux");
+        assert_eq!(gitlab_project_name("dvd+rw-tools"), "dvd-rw-tools");
+        assert_eq!(gitlab_project_name("libc++"), "libcplusplus");
+        assert_eq!(gitlab_project_name("foo__bar"), "foo-bar");
+        assert_eq!(gitlab_project_name("tree"), "unix-tree");
+    }
+}
@@ -1,7 +1,9 @@
+use crate::config::CloneRecovery;
 use anyhow::{Context, Result};
 use colored::*;
 use git2::{FetchOptions, RemoteCallbacks, build::RepoBuilder};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 pub fn clone_repo(url: &str, path: &Path) -> Result<()> {
     println!(":: Downloading {}...", url.cyan());
@@ -20,7 +22,23 @@ pub fn clone_repo(url: &str, path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn pull_repo(path: &Path) -> Result<()> {
+/// Local state of a clone that keeps it from fast-forwarding cleanly
+pub struct LocalChanges {
+    /// Tracked files modified in the working tree
+    pub modified: Vec<String>,
+    /// HEAD has commits the AUR does not (local commits or a force-push)
+    pub diverged: bool,
+}
+
+/// Fetch the AUR repo at `path` and fast-forward to it. If the clone has
+/// local changes or diverged, `recover` decides how to proceed. Changes
+/// that are exactly the result of `patches` do not count, they are
+/// regenerated after the pull.
+pub fn pull_repo(
+    path: &Path,
+    patches: &[PathBuf],
+    recover: impl FnOnce(&LocalChanges) -> Result<CloneRecovery>,
+) -> Result<()> {
     // 1. Open Repository
     let repo = git2::Repository::open(path)
         .context("Failed to open repository")?;
@@ -41,9 +59,11 @@ pub fn pull_repo(path: &Path) -> Result<()> {
     // 5. Merge Analysis
     let (analysis, _) = repo.merge_analysis(&[&fetch_commit])?;
 
+    let modified = local_edits(&repo, patches)?;
+
     if analysis.is_up_to_date() {
         Ok(())
-    } else if analysis.is_fast_forward() {
+    } else if analysis.is_fast_forward() && modified.is_empty() {
         // Fast-forward
         let head = repo.head()?;
         let refname = head.name().ok_or_else(|| anyhow::anyhow!("HEAD reference name invalid"))?;
@@ -54,59 +74,502 @@ pub fn pull_repo(path: &Path) -> Result<()> {
         repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
         Ok(())
     } else {
-        // Diverged or conflict
-        anyhow::bail!("Repository has diverged or requires merge. Manual intervention required.")
+        let changes = LocalChanges {
+            modified,
+            diverged: !analysis.is_fast_forward(),
+        };
+        match recover(&changes)? {
+            CloneRecovery::Keep => Ok(()),
+            CloneRecovery::Reset => reset_to(&repo, path, fetch_commit.id(), false),
+            CloneRecovery::Stash => {
+                reset_to(&repo, path, fetch_commit.id(), !changes.modified.is_empty())
+            }
+        }
     }
 }
 
-pub fn get_diff(path: &Path) -> Result<String> {
-    // 0. Fetch first to ensure FETCH_HEAD is valid
-    let fetch_status = std::process::Command::new("git")
-        .current_dir(path)
-        .arg("fetch")
-        .output()?;
+/// Reference to the commit a clone was at before ax reset it
+const BACKUP_REF: &str = "refs/ax/backup";
+
+/// Message of the stash holding local changes while a clone is reset
+const STASH_MESSAGE: &str = "ax: local changes";
+
+/// Hard-reset the current branch to `target`, keeping the old HEAD as
+/// `refs/ax/backup`. With `stash`, local changes are stashed first and
+/// re-applied on top; if they conflict they stay in `git stash list`.
+fn reset_to(repo: &git2::Repository, path: &Path, target: git2::Oid, stash: bool) -> Result<()> {
+    let head = repo.head()?.peel_to_commit()?;
+    repo.reference(BACKUP_REF, head.id(), true, "ax: before reset")?;
 
-    if !fetch_status.status.success() {
-        return Ok("(Failed to fetch updates, cannot show diff)".to_string());
+    let stashed = stash && git(path, &["stash", "push", "--quiet", "-m", STASH_MESSAGE])?;
+
+    let object = repo.find_object(target, None)?;
+    repo.reset(&object, git2::ResetType::Hard, None)?;
+
+    if stashed && !git(path, &["stash", "pop", "--quiet"])? {
+        repo.reset(&object, git2::ResetType::Hard, None)?;
+        eprintln!(
+            "{} Local changes in {} conflict with the AUR version; they were kept in `git stash list`",
+            "!!".yellow().bold(),
+            path.display()
+        );
     }
+    Ok(())
+}
 
-    // Show diff between HEAD and the upstream we just fetched
-    // Usually 'git diff HEAD..FETCH_HEAD' works after a fetch
-    let status = std::process::Command::new("git")
+/// Run git in `path`, returning whether it succeeded
+fn git(path: &Path, args: &[&str]) -> Result<bool> {
+    let status = Command::new("git")
         .current_dir(path)
-        .args(["diff", "HEAD..FETCH_HEAD", "--color=always"])
-        .output()?;
+        .args(args)
+        .status()
+        .context("Failed to run git")?;
+    Ok(status.success())
+}
 
-    Ok(String::from_utf8_lossy(&status.stdout).to_string())
+/// Tracked files with uncommitted changes
+fn modified_files(repo: &git2::Repository) -> Result<Vec<String>> {
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses
+        .iter()
+        .filter(|entry| entry.status() != git2::Status::CURRENT)
+        .filter_map(|entry| entry.path().map(String::from))
+        .collect())
 }
 
-pub fn check_vcs_update(path: &Path) -> Result<bool> {
-    // 1. Fetch
-    let status = std::process::Command::new("git")
-        .current_dir(path)
-        .arg("fetch")
-        .output()?;
+/// Reference marking the last commit the user reviewed or built
+const REVIEWED_REF: &str = "refs/ax/reviewed";
 
-    if !status.status.success() {
-        return Ok(false); // If fetch fails, assume no update or offline
+/// Remember HEAD as the last reviewed (or built) commit of the clone at `path`
+pub fn mark_reviewed(path: &Path) -> Result<()> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let head = repo.head()?.peel_to_commit()?;
+    repo.reference(REVIEWED_REF, head.id(), true, "ax: mark reviewed")?;
+    Ok(())
+}
+
+/// Remember commit `spec` as the last reviewed (or built) commit of the
+/// clone at `path`, e.g. after building an older commit
+pub fn mark_reviewed_at(path: &Path, spec: &str) -> Result<()> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let commit = repo.revparse_single(spec)?.peel_to_commit()?;
+    repo.reference(REVIEWED_REF, commit.id(), true, "ax: mark reviewed")?;
+    Ok(())
+}
+
+/// Commit currently checked out in the clone at `path`
+pub fn head_commit(path: &Path) -> Option<String> {
+    let repo = git2::Repository::open(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// Branch (e.g. `refs/heads/master`) checked out in the clone at `path`
+pub fn head_ref(path: &Path) -> Option<String> {
+    let repo = git2::Repository::open(path).ok()?;
+    let head = repo.head().ok()?;
+    head.is_branch().then(|| head.name().map(String::from))?
+}
+
+/// Force-checkout `spec` in the clone at `path`: a branch reference is
+/// checked out as such, anything else (a commit id) detaches HEAD
+pub fn checkout(path: &Path, spec: &str) -> Result<()> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let object = repo
+        .revparse_single(spec)
+        .with_context(|| format!("Unknown revision {}", spec))?;
+    repo.checkout_tree(
+        &object,
+        Some(git2::build::CheckoutBuilder::default().force()),
+    )?;
+    if spec.starts_with("refs/") {
+        repo.set_head(spec)?;
+    } else {
+        repo.set_head_detached(object.peel_to_commit()?.id())?;
+    }
+    Ok(())
+}
+
+/// Fetch the full history of a shallow clone
+pub fn unshallow(path: &Path) -> Result<()> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    if !repo.is_shallow() {
+        return Ok(());
     }
 
-    // 2. Compare HEAD and @{upstream}
-    let output = std::process::Command::new("git")
+    println!(":: Fetching full history of {}...", path.display());
+    let status = Command::new("git")
         .current_dir(path)
-        .args(["rev-list", "--left-right", "--count", "HEAD...@{u}"])
-        .output()?;
+        .args(["fetch", "--quiet", "--unshallow"])
+        .status()
+        .context("Failed to run git fetch")?;
+    if !status.success() {
+        anyhow::bail!("Failed to unshallow {:?}", path);
+    }
+    Ok(())
+}
+
+/// A commit of an AUR packaging repo
+pub struct LogEntry {
+    pub id: String,
+    pub summary: String,
+    /// Unix time of the commit
+    pub time: i64,
+    /// `[epoch:]pkgver-pkgrel` from the commit's `.SRCINFO`
+    pub version: Option<String>,
+}
+
+/// The newest `limit` commits reachable from HEAD
+pub fn log(path: &Path, limit: usize) -> Result<Vec<LogEntry>> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+
+    let mut entries = Vec::new();
+    for oid in revwalk.take(limit) {
+        let commit = repo.find_commit(oid?)?;
+        let version = commit
+            .tree()
+            .ok()
+            .and_then(|tree| tree.get_path(Path::new(".SRCINFO")).ok())
+            .and_then(|entry| repo.find_blob(entry.id()).ok())
+            .and_then(|blob| {
+                crate::parser::parse_srcinfo_str(&String::from_utf8_lossy(blob.content())).ok()
+            })
+            .map(|metadata| metadata.version);
+        entries.push(LogEntry {
+            id: commit.id().to_string(),
+            summary: commit.summary().unwrap_or("").to_string(),
+            time: commit.time().seconds(),
+            version,
+        });
+    }
+    Ok(entries)
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    // Output format: "0 5" (0 ahead, 5 behind)
-    let parts: Vec<&str> = stdout.split_whitespace().collect();
+/// Returns the last reviewed commit, if it is still present in the clone
+pub fn get_reviewed_commit(path: &Path) -> Option<git2::Oid> {
+    let repo = git2::Repository::open(path).ok()?;
+    let reference = repo.find_reference(REVIEWED_REF).ok()?;
+    let commit = reference.peel_to_commit().ok()?;
+    Some(commit.id())
+}
+
+/// Whether HEAD of the clone at `path` is the last reviewed commit
+pub fn is_reviewed(path: &Path) -> bool {
+    let Ok(repo) = git2::Repository::open(path) else {
+        return false;
+    };
+    let head = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+    matches!((head, get_reviewed_commit(path)), (Some(head), Some(reviewed)) if head.id() == reviewed)
+}
+
+/// Read `file` as it was in the last reviewed commit
+pub fn read_reviewed_file(path: &Path, file: &str) -> Option<String> {
+    let repo = git2::Repository::open(path).ok()?;
+    let commit = repo.find_commit(get_reviewed_commit(path)?).ok()?;
+    let entry = commit.tree().ok()?.get_path(Path::new(file)).ok()?;
+    let blob = repo.find_blob(entry.id()).ok()?;
+    Some(String::from_utf8_lossy(blob.content()).to_string())
+}
+
+/// Diff the clone at `path` from the last reviewed commit to HEAD.
+///
+/// The output starts with the list of commits that were never reviewed.
+/// If nothing was reviewed yet, the whole tree is shown as added.
+/// Returns an empty string if HEAD has already been reviewed.
+pub fn get_diff(path: &Path) -> Result<String> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let head = repo.head()?.peel_to_commit()?;
+    let reviewed = get_reviewed_commit(path).and_then(|oid| repo.find_commit(oid).ok());
+
+    if reviewed.as_ref().is_some_and(|c| c.id() == head.id()) {
+        return Ok(String::new());
+    }
 
-    if parts.len() >= 2 {
-        let behind: usize = parts[1]
-            .parse()
-            .context("Failed to parse git rev-list output")?;
-        return Ok(behind > 0);
+    let mut out = String::new();
+    match &reviewed {
+        Some(commit) => out.push_str(&format!(
+            "{}\n",
+            format!(
+                ":: Changes since last reviewed commit {}",
+                short_id(commit.id())
+            )
+            .bold()
+        )),
+        None => out.push_str(&format!(
+            "{}\n",
+            ":: No previously reviewed commit - everything below is UNREVIEWED"
+                .red()
+                .bold()
+        )),
+    }
+
+    // List the unreviewed commits (HEAD back to, excluding, the reviewed one)
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head.id())?;
+    if let Some(commit) = &reviewed {
+        revwalk.hide(commit.id())?;
+    }
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        out.push_str(&format!(
+            "   {} {} {}\n",
+            "[unreviewed]".red().bold(),
+            short_id(commit.id()).yellow(),
+            commit.summary().unwrap_or("")
+        ));
     }
+    out.push('\n');
 
-    Ok(false)
+    let old_tree = reviewed.as_ref().map(|c| c.tree()).transpose()?;
+    let new_tree = head.tree()?;
+    let diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)?;
+
+    format_patch(&diff, &mut out)?;
+    Ok(out)
+}
+
+/// Tracked files with uncommitted changes that `patches` applied to HEAD
+/// would not produce, i.e. edits made by hand
+fn local_edits(repo: &git2::Repository, patches: &[PathBuf]) -> Result<Vec<String>> {
+    let modified = modified_files(repo)?;
+    if patches.is_empty() || modified.is_empty() {
+        return Ok(modified);
+    }
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow::anyhow!("Repository has no working tree"))?;
+    // Patches that no longer apply make every change count
+    let Some(patched) = patched_index(workdir, patches)? else {
+        return Ok(modified);
+    };
+
+    Ok(modified
+        .into_iter()
+        .filter(|file| {
+            let expected = patched.get_path(Path::new(file), 0).map(|e| e.id);
+            let actual = git2::Oid::hash_file(git2::ObjectType::Blob, workdir.join(file)).ok();
+            expected != actual
+        })
+        .collect())
+}
+
+/// Index of HEAD with `patches` applied by `git apply --cached` into a
+/// scratch index file, `None` if one does not apply
+fn patched_index(workdir: &Path, patches: &[PathBuf]) -> Result<Option<git2::Index>> {
+    let scratch = crate::tempdir::TempDir::new("ax-patch")?;
+    let index_file = scratch.path().join("index");
+    let git = |args: &[&std::ffi::OsStr]| -> Result<bool> {
+        let status = Command::new("git")
+            .current_dir(workdir)
+            .env("GIT_INDEX_FILE", &index_file)
+            .args(args)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .context("Failed to run git")?;
+        Ok(status.success())
+    };
+
+    if !git(&["read-tree".as_ref(), "HEAD".as_ref()])? {
+        return Ok(None);
+    }
+    for patch in patches {
+        if !git(&["apply".as_ref(), "--cached".as_ref(), patch.as_os_str()])? {
+            return Ok(None);
+        }
+    }
+    Ok(Some(git2::Index::open(&index_file)?))
+}
+
+/// Reset the tracked files of the clone at `path` to HEAD and apply
+/// `patches` on top with `git apply`. Re-applying after a pull is safe.
+/// Edits made by hand on top of the patches are left alone.
+pub fn apply_patches(path: &Path, patches: &[PathBuf]) -> Result<()> {
+    if patches.is_empty() {
+        return Ok(());
+    }
+
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let edits = local_edits(&repo, patches)?;
+    if !edits.is_empty() {
+        eprintln!(
+            "{} Not re-applying patches over local changes in {}: {}",
+            "!!".yellow().bold(),
+            path.display(),
+            edits.join(", ")
+        );
+        return Ok(());
+    }
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+
+    for patch in patches {
+        println!(
+            ":: Applying {}",
+            patch
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .cyan()
+        );
+        let status = Command::new("git")
+            .current_dir(path)
+            .arg("apply")
+            .arg(patch)
+            .status()
+            .context("Failed to run git apply")?;
+        if !status.success() {
+            anyhow::bail!("Failed to apply patch {:?} to {:?}", patch, path);
+        }
+    }
+    Ok(())
+}
+
+/// Diff of the tracked files in the working tree against HEAD, i.e. the
+/// result of local patches. Untracked build artifacts are not included.
+pub fn get_local_changes(path: &Path) -> Result<String> {
+    let repo = git2::Repository::open(path).context("Failed to open repository")?;
+    let head = repo.head()?.peel_to_tree()?;
+    let diff = repo.diff_tree_to_workdir(Some(&head), None)?;
+
+    let mut out = String::new();
+    format_patch(&diff, &mut out)?;
+    Ok(out)
+}
+
+fn format_patch(diff: &git2::Diff, out: &mut String) -> Result<()> {
+    diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+        let content = String::from_utf8_lossy(line.content());
+        match line.origin() {
+            '+' => out.push_str(&format!("{}", format!("+{}", content).green())),
+            '-' => out.push_str(&format!("{}", format!("-{}", content).red())),
+            ' ' => out.push_str(&format!(" {}", content)),
+            'F' => out.push_str(&format!("{}", content.bold())),
+            'H' => out.push_str(&format!("{}", content.cyan())),
+            _ => out.push_str(&content),
+        }
+        true
+    })?;
+    Ok(())
+}
+
+fn short_id(oid: git2::Oid) -> String {
+    oid.to_string().chars().take(8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_file(repo: &git2::Repository, content: &str, message: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join("PKGBUILD"), content).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("PKGBUILD")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("ax", "ax@localhost").unwrap();
+        let parents: Vec<_> = repo
+            .head()
+            .ok()
+            .and_then(|h| h.peel_to_commit().ok())
+            .into_iter()
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    /// Drop the color escapes `colored` may add, whatever its global state
+    fn strip_ansi(text: &str) -> String {
+        let mut out = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // CSI sequences end with a letter, e.g. `\x1b[1;32m`
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_diff_since_reviewed_commit() {
+        let tmp = crate::tempdir::TempDir::new("ax-git-test").unwrap();
+        let dir = tmp.path().join("clone");
+        let repo = git2::Repository::init(&dir).unwrap();
+        let get_diff = |dir: &Path| get_diff(dir).map(|diff| strip_ansi(&diff));
+
+        commit_file(&repo, "pkgver=1\n", "Initial import");
+        let diff = get_diff(&dir).unwrap();
+        assert!(diff.contains("No previously reviewed commit"));
+        assert!(diff.contains("[unreviewed]"));
+        assert!(diff.contains("+pkgver=1"));
+
+        mark_reviewed(&dir).unwrap();
+        assert!(get_diff(&dir).unwrap().is_empty());
+
+        commit_file(&repo, "pkgver=2\n", "Update to 2");
+        let diff = get_diff(&dir).unwrap();
+        assert!(diff.contains("Changes since last reviewed commit"));
+        assert!(diff.contains("Update to 2"));
+        assert!(!diff.contains("Initial import"));
+        assert!(diff.contains("-pkgver=1"));
+        assert!(diff.contains("+pkgver=2"));
+    }
+
+    #[test]
+    fn test_pull_recovers_diverged_clone() {
+        let tmp = crate::tempdir::TempDir::new("ax-git-pull-test").unwrap();
+        let dir = tmp.path();
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        commit_file(&origin, "pkgver=1\n", "Initial import");
+
+        let clone_path = dir.join("clone");
+        let clone =
+            git2::Repository::clone(dir.join("origin").to_str().unwrap(), &clone_path).unwrap();
+        let local = commit_file(&clone, "pkgver=1-local\n", "Local commit");
+        let upstream = commit_file(&origin, "pkgver=2\n", "Update to 2");
+
+        pull_repo(&clone_path, &[], |changes| {
+            assert!(changes.diverged);
+            assert!(changes.modified.is_empty());
+            Ok(CloneRecovery::Keep)
+        })
+        .unwrap();
+        assert_eq!(head_commit(&clone_path), Some(local.to_string()));
+
+        pull_repo(&clone_path, &[], |_| Ok(CloneRecovery::Reset)).unwrap();
+        assert_eq!(head_commit(&clone_path), Some(upstream.to_string()));
+        let backup = clone.find_reference(BACKUP_REF).unwrap();
+        assert_eq!(backup.target(), Some(local));
+    }
+
+    #[test]
+    fn test_patch_result_is_not_a_local_edit() {
+        let dir = crate::tempdir::TempDir::new("ax-git-patch-test").unwrap();
+        let repo = git2::Repository::init(dir.path().join("clone")).unwrap();
+        commit_file(&repo, "pkgver=1\nmakedepends=()\n", "Initial import");
+
+        let patch = dir.path().join("fix.patch");
+        std::fs::write(
+            &patch,
+            "--- a/PKGBUILD\n+++ b/PKGBUILD\n@@ -1,2 +1,2 @@\n pkgver=1\n-makedepends=()\n+makedepends=(cmake)\n",
+        )
+        .unwrap();
+        let patches = vec![patch];
+
+        let pkgbuild = dir.path().join("clone/PKGBUILD");
+        std::fs::write(&pkgbuild, "pkgver=1\nmakedepends=(cmake)\n").unwrap();
+        assert!(local_edits(&repo, &patches).unwrap().is_empty());
+
+        std::fs::write(&pkgbuild, "pkgver=1\nmakedepends=(cmake ninja)\n").unwrap();
+        assert_eq!(local_edits(&repo, &patches).unwrap(), vec!["PKGBUILD"]);
+    }
 }
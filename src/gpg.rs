@@ -1,10 +1,57 @@
+use crate::config::Config;
+use crate::interactive;
+use crate::tempdir::TempDir;
+use anyhow::{Context, Result};
 use colored::*;
+use log::debug;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
-/// Ensure required PGP keys are available in the local keyring.
-/// Returns true if all keys are available, false if some failed
-/// (caller should use --skippgpcheck as fallback).
-pub fn ensure_keys(keys: &[String]) -> anyhow::Result<bool> {
+/// Keyring makepkg verifies source signatures with (`GNUPGHOME`), kept
+/// apart from the user's personal keyring. Created on first use. As root,
+/// makepkg runs as `build_user`, who cannot enter root's home, so the
+/// keyring lives in the build dir and is handed over to that user.
+pub fn keyring_dir(config: &Config) -> Result<PathBuf> {
+    let build_user = config
+        .build_user
+        .as_deref()
+        .filter(|_| crate::privilege::is_root());
+    let dir = match build_user {
+        Some(_) => config.get_cache_dir().join(".gnupg"),
+        None => Config::get_data_dir().join("gnupg"),
+    };
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // Keys imported by root would otherwise leave files build_user can't use
+    if let Some(user) = build_user {
+        let status = Command::new("chown")
+            .arg("-R")
+            .arg(format!("{}:", user))
+            .arg(&dir)
+            .status()
+            .context("Failed to execute chown")?;
+        if !status.success() {
+            anyhow::bail!("Failed to hand {} over to {}", dir.display(), user);
+        }
+    }
+    Ok(dir)
+}
+
+/// Ensure the keys in `validpgpkeys` are in the ax keyring. Missing keys are
+/// taken from the personal keyring, looked up with WKD (if a PKGBUILD
+/// comment names an e-mail address for the key) or on the configured
+/// keyservers, and imported after the user confirmed their owner.
+/// Returns false if some key is still missing.
+pub fn ensure_keys(keys: &[String], cache_dir: &Path, config: &Config) -> Result<bool> {
     if keys.is_empty() {
         return Ok(true);
     }
@@ -15,64 +62,511 @@ pub fn ensure_keys(keys: &[String]) -> anyhow::Result<bool> {
         keys.len()
     );
 
+    let keyring = keyring_dir(config)?;
+    let pkgbuild = fs::read_to_string(cache_dir.join("PKGBUILD")).unwrap_or_default();
     let mut all_ok = true;
 
     for key in keys {
-        // Check if key already exists in local keyring
-        let already_present = Command::new("gpg")
-            .args(["--batch", "--list-keys", key])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .is_ok_and(|s| s.success());
+        let Some(fingerprint) = normalize_fingerprint(key) else {
+            eprintln!(
+                "   {} {} is not a full fingerprint, refusing to fetch it",
+                "✗".red(),
+                key
+            );
+            all_ok = false;
+            continue;
+        };
 
-        if already_present {
-            println!("   {} Key {} already present", "✓".green(), key);
+        if has_key(Some(&keyring), &fingerprint) {
+            println!("   {} Key {} already present", "✓".green(), fingerprint);
             continue;
         }
 
-        println!("   {} Fetching key {}...", "→".yellow(), key);
+        println!("   {} Fetching key {}...", "→".yellow(), fingerprint);
+        let (home, source) =
+            match fetch_key(&fingerprint, &key_emails(&pkgbuild, &fingerprint), config) {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!(
+                        "   {} Failed to fetch key {}: {:#}",
+                        "✗".red(),
+                        fingerprint,
+                        e
+                    );
+                    all_ok = false;
+                    continue;
+                }
+            };
 
-        // First attempt
-        if fetch_key(key) {
-            println!("   {} Key {} imported successfully", "✓".green(), key);
+        show_key(home.path(), &fingerprint, &source);
+        if !confirm_import(config)? {
+            eprintln!("   {} Key {} not imported", "✗".red(), fingerprint);
+            all_ok = false;
             continue;
         }
+        import_key(Some(home.path()), &keyring, &fingerprint)?;
+        println!("   {} Key {} imported", "✓".green(), fingerprint);
+    }
+
+    Ok(all_ok)
+}
+
+/// Print the fingerprint, creation date and user IDs of a fetched key
+fn show_key(home: &Path, fingerprint: &str, source: &str) {
+    println!("   Key found in {}", source.cyan());
+    println!(
+        "      Fingerprint: {}",
+        format_fingerprint(fingerprint).bold()
+    );
+
+    let colons = Command::new("gpg")
+        .arg("--homedir")
+        .arg(home)
+        .args([
+            "--batch",
+            "--with-colons",
+            "--fixed-list-mode",
+            "--list-keys",
+        ])
+        .arg(fingerprint)
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+    let Some(info) = parse_key_info(&colons, fingerprint) else {
+        return;
+    };
+
+    println!(
+        "      Created:     {}",
+        crate::history::format_timestamp(info.created)
+    );
+    match info.validity {
+        'r' => println!("      {}", "This key is revoked".red().bold()),
+        'e' => println!("      {}", "This key is expired".yellow().bold()),
+        _ => {}
+    }
+    for uid in &info.uids {
+        println!("      UID:         {}", uid);
+    }
+}
+
+/// Ask before importing a key. Unattended runs only import with
+/// `auto_import_keys`.
+fn confirm_import(config: &Config) -> Result<bool> {
+    if config.auto_import_keys {
+        return Ok(true);
+    }
+    if config.no_confirm {
+        eprintln!(
+            "   {} Not importing keys without confirmation (set auto_import_keys = true)",
+            "!!".yellow().bold()
+        );
+        return Ok(false);
+    }
+    interactive::prompt_confirm("Import this key into the ax keyring?")
+}
 
-        // First attempt failed — kill ALL gpg daemons aggressively
+/// Decide whether `pkg` may be built with `--skippgpcheck` after its keys
+/// could not be fetched: only with `allow_skippgpcheck` or when confirmed
+pub fn confirm_skip_pgp(pkg: &str, config: &Config) -> Result<()> {
+    if config.allow_skippgpcheck {
         eprintln!(
-            "   {} First attempt failed, restarting GPG daemons...",
-            "→".yellow()
+            "{} Building {} with --skippgpcheck (allow_skippgpcheck is set)",
+            "!!".yellow().bold(),
+            pkg
         );
-        let _ = Command::new("gpgconf").args(["--kill", "all"]).status();
+        return Ok(());
+    }
+    if !config.no_confirm
+        && interactive::prompt_default_no(&format!(
+            "Build {} without verifying source signatures (--skippgpcheck)?",
+            pkg
+        ))?
+    {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Missing PGP keys for {}. Import them manually or set allow_skippgpcheck = true.",
+        pkg
+    );
+}
 
-        // Small delay to let daemons fully shut down
-        std::thread::sleep(std::time::Duration::from_millis(500));
+/// Fetch `fingerprint` into a temporary keyring, which afterwards holds a
+/// key with exactly this fingerprint. Returns it and where the key was found.
+fn fetch_key(fingerprint: &str, emails: &[String], config: &Config) -> Result<(TempHome, String)> {
+    let home = TempHome::new()?;
+    let timeout = Duration::from_secs(config.keyserver_timeout);
 
-        // Retry
-        if fetch_key(key) {
-            println!("   {} Key {} imported on retry", "✓".green(), key);
-        } else {
-            eprintln!("   {} Failed to fetch key {}", "✗".red(), key);
-            all_ok = false;
+    // Keys the user imported before need no network lookup. gpg would
+    // create a missing personal keyring, so only existing ones are read.
+    let personal = std::env::var_os("GNUPGHOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".gnupg")));
+    if personal.is_some_and(|dir| dir.exists())
+        && import_key(None, home.path(), fingerprint).is_ok()
+        && has_key(Some(home.path()), fingerprint)
+    {
+        return Ok((home, "your personal keyring".to_string()));
+    }
+
+    let mut sources: Vec<(String, Vec<&str>)> = Vec::new();
+    for email in emails {
+        sources.push((
+            format!("WKD ({})", email),
+            vec![
+                "--auto-key-locate",
+                "clear,nodefault,wkd",
+                "--locate-keys",
+                email,
+            ],
+        ));
+    }
+    for keyserver in &config.keyservers {
+        sources.push((
+            keyserver.clone(),
+            vec!["--keyserver", keyserver, "--recv-keys", fingerprint],
+        ));
+    }
+    if sources.is_empty() {
+        anyhow::bail!("no keyservers configured");
+    }
+
+    for (source, args) in &sources {
+        debug!("Looking up {} via {}", fingerprint, source);
+        let mut cmd = home.gpg();
+        cmd.args(args);
+        if !run_with_timeout(&mut cmd, timeout) {
+            debug!("Lookup via {} failed or timed out", source);
+            continue;
+        }
+        if has_key(Some(home.path()), fingerprint) {
+            return Ok((home, source.clone()));
         }
+        debug!(
+            "{} did not return a key with fingerprint {}",
+            source, fingerprint
+        );
     }
 
-    Ok(all_ok)
+    let names: Vec<&str> = sources.iter().map(|(source, _)| source.as_str()).collect();
+    anyhow::bail!("not found via {}", names.join(", "))
 }
 
-/// Try to fetch a key from a keyserver. Returns true on success.
-fn fetch_key(key: &str) -> bool {
-    Command::new("gpg")
-        .args([
-            "--batch",
-            "--yes",
-            "--keyserver",
-            "keyserver.ubuntu.com",
-            "--recv-keys",
-            key,
-        ])
+/// Copy `fingerprint` from the keyring in `from` (or the personal one) into
+/// the keyring in `to`
+fn import_key(from: Option<&Path>, to: &Path, fingerprint: &str) -> Result<()> {
+    let mut export = Command::new("gpg");
+    if let Some(from) = from {
+        export.arg("--homedir").arg(from);
+    }
+    let export = export
+        .args(["--batch", "--export", fingerprint])
         .stderr(Stdio::null())
-        .status()
-        .is_ok_and(|s| s.success())
+        .output()
+        .context("Failed to execute gpg --export")?;
+    if !export.status.success() || export.stdout.is_empty() {
+        anyhow::bail!("Failed to export key {}", fingerprint);
+    }
+
+    let mut child = Command::new("gpg")
+        .arg("--homedir")
+        .arg(to)
+        .args(["--batch", "--import"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to execute gpg --import")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&export.stdout)?;
+    }
+    if !child.wait()?.success() {
+        anyhow::bail!("Failed to import key {}", fingerprint);
+    }
+    Ok(())
+}
+
+/// Whether the keyring in `home` (or the personal one) has a key with
+/// exactly this fingerprint
+fn has_key(home: Option<&Path>, fingerprint: &str) -> bool {
+    let mut cmd = Command::new("gpg");
+    if let Some(home) = home {
+        cmd.arg("--homedir").arg(home);
+    }
+    cmd.args(["--batch", "--with-colons", "--list-keys", fingerprint])
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| {
+            fingerprints(&String::from_utf8_lossy(&output.stdout))
+                .iter()
+                .any(|fpr| fpr == fingerprint)
+        })
+}
+
+/// Run `cmd`, killing it after `timeout`. Returns true on success.
+fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> bool {
+    let Ok(mut child) = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    else {
+        return false;
+    };
+
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) if start.elapsed() < timeout => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            }
+        }
+    }
+}
+
+/// Fingerprints of the `fpr` records in `gpg --with-colons` output
+pub fn fingerprints(colons: &str) -> Vec<String> {
+    colons
+        .lines()
+        .filter(|line| line.starts_with("fpr:"))
+        .filter_map(|line| line.split(':').nth(9))
+        .filter(|fpr| !fpr.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// A key as listed by `gpg --with-colons --fixed-list-mode`
+#[derive(Debug, PartialEq)]
+pub struct KeyInfo {
+    /// Validity of the primary key, e.g. `r` for revoked or `e` for expired
+    pub validity: char,
+    /// Creation time of the primary key (seconds since the epoch)
+    pub created: u64,
+    pub uids: Vec<String>,
+}
+
+/// Parse the key with primary `fingerprint` out of `gpg --with-colons` output
+pub fn parse_key_info(colons: &str, fingerprint: &str) -> Option<KeyInfo> {
+    // The key being read and the fingerprint of its primary key
+    let mut key: Option<(KeyInfo, Option<String>)> = None;
+    for line in colons.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+        match fields[0] {
+            "pub" => {
+                if let Some((info, Some(fpr))) = key.take()
+                    && fpr == fingerprint
+                {
+                    return Some(info);
+                }
+                let info = KeyInfo {
+                    validity: field(1).chars().next().unwrap_or('-'),
+                    created: field(5).parse().unwrap_or(0),
+                    uids: Vec::new(),
+                };
+                key = Some((info, None));
+            }
+            // Later fpr records belong to subkeys
+            "fpr" => {
+                if let Some((_, primary @ None)) = &mut key {
+                    *primary = Some(field(9).to_string());
+                }
+            }
+            "uid" => {
+                if let Some((info, _)) = &mut key {
+                    info.uids.push(unescape_colons(field(9)));
+                }
+            }
+            _ => {}
+        }
+    }
+    key.filter(|(_, fpr)| fpr.as_deref() == Some(fingerprint))
+        .map(|(info, _)| info)
+}
+
+/// Decode the `\xHH` escapes gpg uses in `--with-colons` fields
+fn unescape_colons(field: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'\\'
+            && tail.first() == Some(&b'x')
+            && let Some(hex) = tail.get(1..3)
+            && let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+        {
+            bytes.push(byte);
+            rest = &tail[3..];
+            continue;
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Fingerprint in groups of four, as gpg prints it
+fn format_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Uppercase `key` without spaces or `0x`, if it is a full v4 or v5 fingerprint
+pub fn normalize_fingerprint(key: &str) -> Option<String> {
+    let key: String = key.split_whitespace().collect();
+    let key = key
+        .strip_prefix("0x")
+        .or_else(|| key.strip_prefix("0X"))
+        .unwrap_or(&key)
+        .to_ascii_uppercase();
+    (matches!(key.len(), 40 | 64) && key.chars().all(|c| c.is_ascii_hexdigit())).then_some(key)
+}
+
+/// E-mail addresses given for `fingerprint` in the PKGBUILD, as in
+/// `'ABCD...' # Jane Doe <jane@example.org>` or a comment line above it
+pub fn key_emails(pkgbuild: &str, fingerprint: &str) -> Vec<String> {
+    let lines: Vec<&str> = pkgbuild.lines().collect();
+    let mut emails = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let (code, comment) = line.split_once('#').unwrap_or((line, ""));
+        let compact: String = code.split_whitespace().collect();
+        if !compact.to_ascii_uppercase().contains(fingerprint) {
+            continue;
+        }
+
+        let mut comments = vec![comment];
+        if i > 0
+            && let Some(above) = lines[i - 1].trim().strip_prefix('#')
+        {
+            comments.push(above);
+        }
+        for comment in comments {
+            if let Some((_, rest)) = comment.split_once('<')
+                && let Some((email, _)) = rest.split_once('>')
+                && email.contains('@')
+                && !emails.iter().any(|e| e == email)
+            {
+                emails.push(email.to_string());
+            }
+        }
+    }
+    emails
+}
+
+/// A throwaway GnuPG home for key lookups. Its daemons are stopped and the
+/// directory removed on drop, leaving the user's own agents alone.
+struct TempHome {
+    dir: TempDir,
+}
+
+impl TempHome {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            dir: TempDir::new("ax-gpg")?,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn gpg(&self) -> Command {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--homedir").arg(self.path()).arg("--batch");
+        cmd
+    }
+}
+
+impl Drop for TempHome {
+    fn drop(&mut self) {
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(self.path())
+            .args(["--kill", "all"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        // The directory itself is removed when `dir` is dropped afterwards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FPR: &str = "ABAF11C65A2970B130ABE3C479BE3E4300411886";
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        assert_eq!(normalize_fingerprint(FPR).as_deref(), Some(FPR));
+        assert_eq!(
+            normalize_fingerprint("0xabaf 11c6 5a29 70b1 30ab  e3c4 79be 3e43 0041 1886")
+                .as_deref(),
+            Some(FPR)
+        );
+        assert_eq!(normalize_fingerprint("79BE3E4300411886"), None);
+        assert_eq!(normalize_fingerprint(&FPR.replace('A', "Z")), None);
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let colons = "pub:-:4096:1:79BE3E4300411886:1316213006:::-:::scESC::::::23::0:\n\
+                      fpr:::::::::ABAF11C65A2970B130ABE3C479BE3E4300411886:\n\
+                      uid:-::::1316213006::0::Linus Torvalds <torvalds@kernel.org>::::::::::0:\n";
+        assert_eq!(fingerprints(colons), [FPR]);
+    }
+
+    #[test]
+    fn test_parse_key_info() {
+        let colons = "tru::1:1700000000:0:3:1:5\n\
+                      pub:e:1024:17:1111111111111111:1000000000:::-:::scESC::::::23::0:\n\
+                      fpr:::::::::1111111111111111111111111111111111111111:\n\
+                      uid:e::::1000000000::0::Old Key <old@example.org>::::::::::0:\n\
+                      pub:-:4096:1:79BE3E4300411886:1316213006:::-:::scESC::::::23::0:\n\
+                      fpr:::::::::ABAF11C65A2970B130ABE3C479BE3E4300411886:\n\
+                      uid:-::::1316213006::0::Linus Torvalds <torvalds@kernel.org>::::::::::0:\n\
+                      uid:-::::1316213006::0::Name\\x3a Nick <nick@example.org>::::::::::0:\n\
+                      sub:-:4096:1:88BCE80F012F54CA:1316213006::::::e::::::23:\n\
+                      fpr:::::::::4AB1D58E2B2DB37F0B40B2B388BCE80F012F54CA:\n";
+
+        let info = parse_key_info(colons, FPR).unwrap();
+        assert_eq!(info.validity, '-');
+        assert_eq!(info.created, 1316213006);
+        assert_eq!(
+            info.uids,
+            [
+                "Linus Torvalds <torvalds@kernel.org>",
+                "Name: Nick <nick@example.org>"
+            ]
+        );
+
+        let old = parse_key_info(colons, "1111111111111111111111111111111111111111").unwrap();
+        assert_eq!(old.validity, 'e');
+        assert!(parse_key_info(colons, "4AB1D58E2B2DB37F0B40B2B388BCE80F012F54CA").is_none());
+    }
+
+    #[test]
+    fn test_key_emails() {
+        let pkgbuild = format!(
+            "validpgpkeys=(\n  '{}' # Linus Torvalds <torvalds@kernel.org>\n  \
+             # Greg Kroah-Hartman <gregkh@kernel.org>\n  '647F28654894E3BD457199BE38DBBDC86092693E'\n)\n",
+            FPR
+        );
+        assert_eq!(key_emails(&pkgbuild, FPR), ["torvalds@kernel.org"]);
+        assert_eq!(
+            key_emails(&pkgbuild, "647F28654894E3BD457199BE38DBBDC86092693E"),
+            ["gregkh@kernel.org"]
+        );
+        assert!(key_emails("validpgpkeys=('ABCD')", FPR).is_empty());
+    }
 }
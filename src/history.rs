@@ -0,0 +1,543 @@
+use crate::arch::ArchDB;
+use crate::clean;
+use crate::config::Config;
+use crate::interactive;
+use crate::pkgname::PkgName;
+use crate::privilege;
+use crate::tempdir::TempDir;
+use anyhow::{Context, Result};
+use colored::*;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where pacman keeps downloaded and `-U`-installed packages
+const PACMAN_CACHE: &str = "/var/cache/pacman/pkg";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    /// Some packages failed and were skipped
+    Partial,
+    Failed,
+}
+
+/// A package built (and usually installed) by a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Artifact {
+    pub pkgbase: String,
+    pub pkgname: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub sha256: Option<String>,
+    /// Version installed before the run, `None` if the package was new
+    pub previous: Option<String>,
+    pub installed: bool,
+}
+
+/// One ax run in the history log (`history.jsonl` in the data dir)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// Unix time the run started
+    pub timestamp: u64,
+    pub command: Vec<String>,
+    pub repo_deps: Vec<String>,
+    pub build_order: Vec<String>,
+    /// Repo dependencies that were not installed before the run
+    #[serde(default)]
+    pub new_repo_deps: Vec<String>,
+    pub artifacts: Vec<Artifact>,
+    /// Commit of the AUR clone each pkgbase was built from
+    pub commits: BTreeMap<String, String>,
+    #[serde(default)]
+    pub skipped: Vec<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Start an entry for the current command line
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            command: std::env::args().collect(),
+            repo_deps: Vec::new(),
+            build_order: Vec::new(),
+            new_repo_deps: Vec::new(),
+            artifacts: Vec::new(),
+            commits: BTreeMap::new(),
+            skipped: Vec::new(),
+            outcome: Outcome::Success,
+            error: None,
+        }
+    }
+
+    /// Record the packages built for `pkgbase` from its clone at `cache_path`
+    pub fn record_build(
+        &mut self,
+        pkgbase: &str,
+        cache_path: &Path,
+        package_paths: &[PathBuf],
+        arch_db: &ArchDB,
+        installed: bool,
+    ) {
+        if let Some(commit) = crate::git_ops::head_commit(cache_path) {
+            self.commits.insert(pkgbase.to_string(), commit);
+        }
+        for path in package_paths {
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let (Some(pkgname), Some(version)) = (
+                clean::artifact_pkgname(&file_name),
+                clean::artifact_version(&file_name),
+            ) else {
+                continue;
+            };
+            self.artifacts.push(Artifact {
+                pkgbase: pkgbase.to_string(),
+                previous: arch_db.get_installed_version(&pkgname),
+                pkgname,
+                version,
+                path: path.clone(),
+                sha256: sha256sum(path),
+                installed,
+            });
+        }
+    }
+
+    /// Mark the artifacts at `package_paths` as installed
+    pub fn mark_installed(&mut self, package_paths: &[PathBuf]) {
+        for artifact in &mut self.artifacts {
+            if package_paths.contains(&artifact.path) {
+                artifact.installed = true;
+            }
+        }
+    }
+
+    /// Set the outcome from the result of the run and append the entry to
+    /// the log. Failing to write the log only warns.
+    pub fn finish(mut self, result: &Result<()>) {
+        match result {
+            Ok(()) if self.skipped.is_empty() => self.outcome = Outcome::Success,
+            Ok(()) => self.outcome = Outcome::Partial,
+            Err(e) => {
+                self.outcome = Outcome::Failed;
+                self.error = Some(format!("{:#}", e));
+            }
+        }
+        if let Err(e) = append(&mut self) {
+            eprintln!("{} Failed to write history log: {:#}", "!!".yellow(), e);
+        }
+    }
+}
+
+fn path() -> PathBuf {
+    Config::get_data_dir().join("history.jsonl")
+}
+
+/// All runs in the log, oldest first
+pub fn load() -> Result<Vec<HistoryEntry>> {
+    let path = path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = fs::File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+    parse(BufReader::new(file))
+}
+
+fn parse(reader: impl BufRead) -> Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // A torn last line must not hide the rest of the history
+            Err(e) => log::warn!("Skipping invalid history line {}: {}", i + 1, e),
+        }
+    }
+    Ok(entries)
+}
+
+/// Append `entry` to the log, assigning it the next id
+fn append(entry: &mut HistoryEntry) -> Result<()> {
+    let path = path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    file.lock_exclusive()?;
+
+    let last_id = parse(BufReader::new(&file))?
+        .last()
+        .map(|last| last.id)
+        .unwrap_or(0);
+    entry.id = last_id + 1;
+
+    let result = writeln!(file, "{}", serde_json::to_string(entry)?).and_then(|_| file.flush());
+    file.unlock()?;
+    Ok(result?)
+}
+
+fn sha256sum(path: &Path) -> Option<String> {
+    let output = Command::new("sha256sum").arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(String::from)
+}
+
+/// Print the logged runs
+pub fn list() -> Result<()> {
+    let entries = load()?;
+    if entries.is_empty() {
+        println!(":: No runs recorded yet.");
+        return Ok(());
+    }
+
+    for entry in &entries {
+        let outcome = match entry.outcome {
+            Outcome::Success => "success".green(),
+            Outcome::Partial => "partial".yellow(),
+            Outcome::Failed => "failed".red(),
+        };
+        println!(
+            "{} {} [{}] {}",
+            format!("#{}", entry.id).bold(),
+            format_timestamp(entry.timestamp).cyan(),
+            outcome,
+            entry.command.join(" ")
+        );
+        for artifact in entry.artifacts.iter().filter(|a| a.installed) {
+            match &artifact.previous {
+                Some(previous) => println!(
+                    "   {} {} -> {}",
+                    artifact.pkgname, previous, artifact.version
+                ),
+                None => println!("   {} {} (new)", artifact.pkgname, artifact.version),
+            }
+        }
+        for name in &entry.new_repo_deps {
+            println!("   {} (new, repo)", name);
+        }
+        for pkgbase in &entry.skipped {
+            println!("   {} (skipped)", pkgbase);
+        }
+        if let Some(error) = &entry.error {
+            println!("   {} {}", "!!".red(), error);
+        }
+    }
+    Ok(())
+}
+
+/// Revert run `id`: reinstall the previously installed versions from the
+/// build dir or pacman's cache and remove the packages the run added
+pub fn undo(id: u64, config: &Config) -> Result<()> {
+    let entries = load()?;
+    let entry = entries
+        .iter()
+        .find(|entry| entry.id == id)
+        .with_context(|| format!("No run #{} in the history log", id))?;
+
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    let mut undo_entry = HistoryEntry::new();
+    // Packages are checked and installed from private copies, so they
+    // cannot be swapped between the check and pacman -U
+    let staging = TempDir::new("ax-undo")?;
+    let mut reinstall = Vec::new();
+    let mut unverified = Vec::new();
+    let mut remove = Vec::new();
+    let mut missing = Vec::new();
+
+    for artifact in entry.artifacts.iter().filter(|a| a.installed) {
+        let Some(current) = arch_db.get_installed_version(&artifact.pkgname) else {
+            continue;
+        };
+        match &artifact.previous {
+            Some(previous) if *previous != current => {
+                // The history file is plain JSON, its pkgbase names a directory
+                let pkgbase = PkgName::new(&artifact.pkgbase)
+                    .with_context(|| format!("Cannot undo run #{}", id))?;
+                let candidates = find_artifacts(config, &pkgbase, &artifact.pkgname, previous);
+                if candidates.is_empty() {
+                    missing.push(format!("{}-{}", artifact.pkgname, previous));
+                    continue;
+                }
+
+                let Some(package) = stage_verified(
+                    &entries,
+                    candidates,
+                    &artifact.pkgname,
+                    previous,
+                    staging.path(),
+                ) else {
+                    anyhow::bail!(
+                        "Cannot undo run #{}: no cached package of {}-{} matches the checksum \
+                         recorded when it was built",
+                        id,
+                        artifact.pkgname,
+                        previous
+                    );
+                };
+                if !package.verified {
+                    unverified.push(package.path.clone());
+                }
+
+                undo_entry.artifacts.push(Artifact {
+                    pkgbase: artifact.pkgbase.clone(),
+                    pkgname: artifact.pkgname.clone(),
+                    version: previous.clone(),
+                    sha256: Some(package.sha256),
+                    path: package.path.clone(),
+                    previous: Some(current),
+                    installed: true,
+                });
+                reinstall.push((package.path, package.staged));
+            }
+            Some(_) => {}
+            None => remove.push(artifact.pkgname.clone()),
+        }
+    }
+    remove.extend(
+        entry
+            .new_repo_deps
+            .iter()
+            .filter(|name| arch_db.get_installed_version(name).is_some())
+            .cloned(),
+    );
+
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Cannot undo run #{}: no cached package for {}",
+            id,
+            missing.join(", ")
+        );
+    }
+    if reinstall.is_empty() && remove.is_empty() {
+        println!(":: Nothing to undo for run #{}.", id);
+        return Ok(());
+    }
+
+    println!("\n{} #{}", ":: Undoing run".blue().bold(), id);
+    for (path, _) in &reinstall {
+        if unverified.contains(path) {
+            println!(
+                "   reinstall {} {}",
+                path.display(),
+                "(not built by ax, no checksum to compare)".yellow()
+            );
+        } else {
+            println!("   reinstall {}", path.display());
+        }
+    }
+    for name in &remove {
+        println!("   remove    {}", name);
+    }
+    if !unverified.is_empty() {
+        if config.no_confirm {
+            anyhow::bail!(
+                "Cannot undo run #{} with --noconfirm: some packages have no recorded checksum",
+                id
+            );
+        }
+        if !interactive::prompt_default_no("Install packages without a recorded checksum?")? {
+            return Ok(());
+        }
+    } else if !config.no_confirm && !interactive::prompt_confirm("Proceed?")? {
+        return Ok(());
+    }
+
+    let staged: Vec<PathBuf> = reinstall.into_iter().map(|(_, staged)| staged).collect();
+    let result = run_undo(config, &staged, &remove);
+    undo_entry.finish(&result);
+    result
+}
+
+fn run_undo(config: &Config, reinstall: &[PathBuf], remove: &[String]) -> Result<()> {
+    if !reinstall.is_empty() {
+        let mut cmd = privilege::pacman(config, true)?;
+        cmd.arg("-U").args(reinstall);
+        if config.no_confirm {
+            cmd.arg("--noconfirm");
+        }
+        let status = cmd.status().context("Failed to execute pacman -U")?;
+        if !status.success() {
+            anyhow::bail!("Failed to reinstall the previous packages");
+        }
+    }
+    if !remove.is_empty() {
+        let mut cmd = privilege::pacman(config, true)?;
+        cmd.arg("-R").args(remove);
+        if config.no_confirm {
+            cmd.arg("--noconfirm");
+        }
+        let status = cmd.status().context("Failed to execute pacman -R")?;
+        if !status.success() {
+            anyhow::bail!("Failed to remove the packages added by the run");
+        }
+    }
+    Ok(())
+}
+
+/// The hash an earlier run recorded for `pkgname` at `version`, if ax
+/// built it. Packages from elsewhere (e.g. pacman's cache) have none.
+fn recorded_hash(entries: &[HistoryEntry], pkgname: &str, version: &str) -> Option<String> {
+    entries
+        .iter()
+        .rev()
+        .flat_map(|entry| &entry.artifacts)
+        .filter(|a| a.pkgname == pkgname && a.version == version)
+        .find_map(|a| a.sha256.clone())
+}
+
+/// Cached package files of `pkgname` at `version` in the clone, `pkgdest`
+/// and pacman's package cache, in that order
+fn find_artifacts(
+    config: &Config,
+    pkgbase: &PkgName,
+    pkgname: &str,
+    version: &str,
+) -> Vec<PathBuf> {
+    let mut dirs = vec![config.get_cache_dir().join(pkgbase)];
+    if let Some(pkgdest) = &config.pkgdest {
+        dirs.push(PathBuf::from(pkgdest));
+    }
+    dirs.push(PathBuf::from(PACMAN_CACHE));
+
+    dirs.iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            clean::artifact_pkgname(&file_name).as_deref() == Some(pkgname)
+                && clean::artifact_version(&file_name).as_deref() == Some(version)
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// A cached package copied into a private directory, so it cannot be
+/// swapped between checking its hash and `pacman -U`
+pub struct StagedPackage {
+    /// Where the package was found
+    pub path: PathBuf,
+    /// The private copy to install
+    pub staged: PathBuf,
+    pub sha256: String,
+    /// Whether the hash matched one recorded when ax built the package
+    pub verified: bool,
+}
+
+/// Stage the first of `candidates` (files of `pkgname` at `version`) whose
+/// copy in `dir` has the hash recorded when ax built it. Without a recorded
+/// hash the first candidate is taken, unverified. `None` if none matches.
+pub fn stage_verified(
+    entries: &[HistoryEntry],
+    candidates: Vec<PathBuf>,
+    pkgname: &str,
+    version: &str,
+    dir: &Path,
+) -> Option<StagedPackage> {
+    let recorded = recorded_hash(entries, pkgname, version);
+    candidates.into_iter().find_map(|path| {
+        let staged = stage(&path, dir)?;
+        let sha256 = sha256sum(&staged)?;
+        match &recorded {
+            Some(recorded) if *recorded != sha256 => None,
+            _ => Some(StagedPackage {
+                path,
+                staged,
+                sha256,
+                verified: recorded.is_some(),
+            }),
+        }
+    })
+}
+
+/// Copy `path` (and its signature, if any) into `dir`
+fn stage(path: &Path, dir: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?;
+    let staged = dir.join(file_name);
+    fs::copy(path, &staged).ok()?;
+
+    let mut sig = file_name.to_os_string();
+    sig.push(".sig");
+    let _ = fs::copy(path.with_file_name(&sig), dir.join(&sig));
+    Some(staged)
+}
+
+/// `YYYY-MM-DD HH:MM` (UTC) of a Unix timestamp
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_timestamp(1_792_332_000), "2026-10-18 14:00");
+    }
+
+    #[test]
+    fn test_parse_skips_invalid_lines() {
+        let mut entry = HistoryEntry::new();
+        entry.id = 1;
+        let log = format!(
+            "{}\n{{\"id\": 2, \"tim\n",
+            serde_json::to_string(&entry).unwrap()
+        );
+        let entries = parse(log.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, 1);
+    }
+}
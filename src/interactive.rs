@@ -1,8 +1,11 @@
 use crate::api::AurPackage;
 use crate::arch::RepoPackage;
+use crate::config::CloneRecovery;
 use anyhow::Result;
 use colored::*;
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 pub enum SearchResult {
     Repo(RepoPackage),
@@ -162,6 +165,130 @@ pub fn prompt_continue() -> Result<bool> {
     }
 }
 
+/// Ask a yes/no question, defaulting to yes
+pub fn prompt_confirm(question: &str) -> Result<bool> {
+    print!(":: {} [Y/n] ", question);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim().to_lowercase();
+
+    Ok(input == "y" || input == "yes" || input.is_empty())
+}
+
+/// Ask for one of `max` numbered choices. Returns `None` on empty or
+/// invalid input.
+pub fn prompt_choice(question: &str, max: usize) -> Result<Option<usize>> {
+    print!(":: {} [1-{}] ", question, max);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|idx| (1..=max).contains(idx))
+        .map(|idx| idx - 1))
+}
+
+/// Ask how to update a clone with local changes. Stashing is only offered
+/// if there are modified files (`dirty`).
+pub fn prompt_clone_recovery(dirty: bool) -> Result<CloneRecovery> {
+    if dirty {
+        print!(":: [S]tash and re-apply local changes, [r]eset, or [k]eep local version? [S/r/k] ");
+    } else {
+        print!(":: [R]eset to the AUR or [k]eep the local version? [R/k] ");
+    }
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim().to_lowercase();
+
+    match input.as_str() {
+        "k" | "keep" => Ok(CloneRecovery::Keep),
+        "r" | "reset" => Ok(CloneRecovery::Reset),
+        _ if dirty => Ok(CloneRecovery::Stash),
+        _ => Ok(CloneRecovery::Reset),
+    }
+}
+
+/// Ask a yes/no question, defaulting to no
+pub fn prompt_default_no(question: &str) -> Result<bool> {
+    print!(":: {} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim().to_lowercase();
+
+    Ok(input == "y" || input == "yes")
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReviewMode {
+    Pager,
+    Editor,
+    Skip,
+}
+
+pub fn prompt_review_mode() -> Result<ReviewMode> {
+    print!(":: Review changes in [P]ager, [e]ditor, or [s]kip? [P/e/s] ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim().to_lowercase();
+
+    match input.as_str() {
+        "e" | "editor" => Ok(ReviewMode::Editor),
+        "s" | "skip" => Ok(ReviewMode::Skip),
+        _ => Ok(ReviewMode::Pager),
+    }
+}
+
+/// Show text in `less`, falling back to printing it directly
+pub fn show_in_pager(text: &str) -> Result<()> {
+    match Command::new("less")
+        .arg("-R") // Raw control chars for color
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(mut pager) => {
+            if let Some(mut stdin) = pager.stdin.take() {
+                let _ = write!(stdin, "{}", text);
+            }
+            pager.wait()?;
+        }
+        Err(_) => {
+            println!(":: (Pager failed, showing raw output)");
+            println!("{}", text);
+        }
+    }
+    Ok(())
+}
+
+/// Open `files` in the user's editor
+pub fn open_editor(editor: &str, files: &[PathBuf]) -> Result<()> {
+    // Use sh -c to allow arguments in EDITOR (e.g., "code --wait")
+    // Pass the file paths as arguments to sh to prevent shell injection
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg("--")
+        .args(files)
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to open editor {}: {}", editor, e))?;
+
+    if !status.success() {
+        eprintln!("{}", "!! Editor exited with error.".red());
+        anyhow::bail!("Editor failed with status: {}", status);
+    }
+    Ok(())
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ErrorAction {
     Retry,
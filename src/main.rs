@@ -1,24 +1,38 @@
 use anyhow::{Context, Result};
 use clap::{CommandFactory, Parser};
 use colored::*;
-use std::process::Command;
 
 mod api;
 mod arch;
 mod builder;
+mod clean;
 mod config;
+mod devel;
+mod downgrade;
+mod elf;
+mod getpkgbuild;
 mod git_ops;
 mod gpg;
 mod graph;
+mod history;
 mod interactive;
 mod lock;
 mod news;
+mod orphans;
 mod parser;
+mod pkgname;
+mod privilege;
+mod rebuild;
 mod resolver;
+mod review;
+mod scanner;
+mod stats;
+mod tempdir;
 mod upgrade;
+mod verify;
 
 mod cli;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, ConfigAction, HistoryAction};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -29,36 +43,146 @@ async fn main() -> Result<()> {
     // We bind it to a variable so it stays alive until end of main
     let _lock = lock::Lock::acquire()?;
 
-    let mut config = config::Config::load()?;
-    check_tools()?;
+    let cli = Cli::parse();
+
+    // Handled before loading the config so a broken config.toml can be fixed
+    if let Some(Commands::Config { action }) = &cli.command {
+        return handle_config_command(action, &cli.config_overrides);
+    }
+
+    let mut config = config::Config::load_layered(&cli.config_overrides)?;
+    check_tools(&config)?;
     let is_interactive = check_interactive()?;
     if !is_interactive {
         config.no_confirm = true;
     }
-    let args = preprocess_args(std::env::args());
-    let cli = Cli::parse_from(args);
 
     match cli.command {
         Some(Commands::Sync {
-            refresh: _,
-            sysupgrade,
+            sync,
+            common,
+            transaction,
+            upgrade,
             cleanbuild,
-            packages,
+            rebuildcheck,
+            devel,
+            vcspkgver,
+            sudoloop,
+            gendb,
+            mflags,
+            makepkg_conf,
+            makepkg,
+            dry_run,
+            pkgdest,
+            reset_clones,
+            ignorearch,
+            packages: pkg_names,
         }) => {
-            // Separate pacman flags from package names
-            let (pacman_flags, pkg_names): (Vec<String>, Vec<String>) =
-                packages.into_iter().partition(|arg| arg.starts_with('-'));
+            // Options forwarded to every pacman transaction
+            let pacman_flags =
+                [common.to_args(), transaction.to_args(), upgrade.to_args()].concat();
 
-            // Detect --noconfirm and propagate to config
-            if pacman_flags.iter().any(|f| f == "--noconfirm") {
+            if common.noconfirm {
                 config.no_confirm = true;
             }
 
+            // -Sc cleans pacman's cache and the AUR build dir, -Scc everything
+            if sync.clean > 0 {
+                if !dry_run {
+                    sync_passthrough(&sync, &pacman_flags, &pkg_names, &config).await?;
+                }
+                let keep = if sync.clean > 1 {
+                    0
+                } else {
+                    config.keep_artifacts as usize
+                };
+                return clean::clean_build_dir(&config, keep, dry_run);
+            }
+
+            // Operations other than installing and upgrading are pacman's
+            if sync.search || sync.info > 0 || sync.groups > 0 || sync.list || transaction.print {
+                return sync_passthrough(&sync, &pacman_flags, &pkg_names, &config).await;
+            }
+
+            // ArchDB and the devel, history and makedeps databases describe this system
+            if let Some(flag) = common.foreign_root() {
+                anyhow::bail!(
+                    "{} is not supported by ax -S, which reads this system's databases. Use pacman.",
+                    flag
+                );
+            }
+
+            // Rejected before anything runs, since targets end up in paths and URLs
+            let pkg_names = pkgname::PkgName::parse_all(&pkg_names)?;
+
             if cleanbuild {
                 config.clean_build = true;
             }
 
-            if sysupgrade {
+            if devel {
+                config.devel = true;
+            }
+
+            if vcspkgver {
+                config.vcs_pkgver = true;
+            }
+
+            if sudoloop {
+                config.sudoloop = true;
+            }
+
+            if let Some(flags) = mflags {
+                config
+                    .makepkg_flags
+                    .extend(flags.split_whitespace().map(String::from));
+            }
+            if makepkg_conf.is_some() {
+                config.makepkg_conf = makepkg_conf;
+            }
+            if makepkg.is_some() {
+                config.makepkg_bin = makepkg;
+            }
+
+            if let Some(dir) = pkgdest {
+                // makepkg runs in the clone, so PKGDEST must be absolute
+                std::fs::create_dir_all(&dir)?;
+                let dir = std::fs::canonicalize(&dir)?;
+                config.pkgdest = Some(dir.to_string_lossy().to_string());
+            }
+
+            if sync.downloadonly {
+                config.build_only = true;
+            }
+            if reset_clones {
+                config.reset_clones = true;
+            }
+            if ignorearch {
+                config.ignore_arch = true;
+            }
+
+            // Held until the end of the transaction, dropping it stops the loop
+            let _sudo_loop = if config.sudoloop {
+                privilege::SudoLoop::start(&config)?
+            } else {
+                None
+            };
+
+            if gendb {
+                devel::gendb(&config)?;
+            }
+
+            if sync.refresh > 0 && sync.sysupgrade == 0 {
+                let mut cmd = privilege::pacman(&config, true)?;
+                cmd.arg("-S")
+                    .args(vec!["--refresh"; sync.refresh as usize])
+                    .args(common.to_args());
+                let status = cmd.status().context("Failed to execute pacman -Sy")?;
+                if !status.success() {
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+            }
+
+            if sync.sysupgrade > 0 {
                 if config.show_news
                     && let Err(e) = news::check_news().await
                 {
@@ -66,96 +190,186 @@ async fn main() -> Result<()> {
                 }
 
                 println!("{}", ":: Starting system upgrade...".blue().bold());
-                let mut cmd = Command::new("sudo");
-                cmd.arg("pacman").arg("-Syu");
+                let mut cmd = privilege::pacman(&config, true)?;
+                cmd.arg("-S").args(sync.to_args()).args(&pacman_flags);
 
-                // Forward user-provided pacman flags
-                for flag in &pacman_flags {
-                    cmd.arg(flag);
-                }
-
-                let status = cmd.status().context("Failed to execute sudo pacman -Syu")?;
+                let status = cmd.status().context("Failed to execute pacman -Syu")?;
 
                 if !status.success() {
                     anyhow::bail!("System upgrade failed");
                 }
 
                 println!("{}", ":: Checking for AUR updates...".blue().bold());
-                match upgrade::check_updates(&config).await {
+                match upgrade::check_updates(&config, &upgrade.ignored()).await {
                     Ok(updates) => {
                         if !updates.is_empty() {
-                            install_packages(&updates, &config, &pacman_flags).await?;
+                            install_packages(
+                                &updates.targets(),
+                                &config,
+                                &pacman_flags,
+                                &updates.devel,
+                            )
+                            .await?;
                         }
                     }
                     Err(e) => eprintln!("{} {:#}", "!! Upgrade check failed:".red().bold(), e),
                 }
             }
 
-            if !pkg_names.is_empty() {
-                install_packages(&pkg_names, &config, &pacman_flags).await?;
+            if rebuildcheck {
+                let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
+                let candidates = rebuild::find_broken_packages(&arch_db)?;
+                rebuild::show_candidates(&candidates);
+
+                if !candidates.is_empty() {
+                    // Installed pkgnames are targets, so split pkgbases resolve too
+                    let rebuild_names: Vec<pkgname::PkgName> = candidates
+                        .iter()
+                        .flat_map(|c| &c.pkgnames)
+                        .filter_map(|name| pkgname::PkgName::new(name).ok())
+                        .collect();
+                    let rebuild_bases: Vec<pkgname::PkgName> = candidates
+                        .iter()
+                        .filter_map(|c| pkgname::PkgName::new(&c.pkgbase).ok())
+                        .collect();
+                    install_packages(&rebuild_names, &config, &pacman_flags, &rebuild_bases)
+                        .await?;
+                }
+            }
+
+            if !pkg_names.is_empty() && config.build_only {
+                // -Sw: pacman downloads repo targets, AUR targets are only built
+                let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
+                let (repo_targets, aur_targets): (Vec<_>, Vec<_>) = pkg_names
+                    .into_iter()
+                    .partition(|name| arch_db.exists_in_repo(name));
+
+                if !repo_targets.is_empty() {
+                    let status = privilege::pacman(&config, true)?
+                        .args(["-S", "--downloadonly"])
+                        .args(&pacman_flags)
+                        .args(repo_targets.iter().map(|name| name.as_str()))
+                        .status()
+                        .context("Failed to execute pacman -Sw")?;
+                    if !status.success() {
+                        std::process::exit(status.code().unwrap_or(1));
+                    }
+                }
+                if !aur_targets.is_empty() {
+                    install_packages(&aur_targets, &config, &pacman_flags, &[]).await?;
+                }
+            } else if !pkg_names.is_empty() {
+                install_packages(&pkg_names, &config, &pacman_flags, &[]).await?;
+            }
+        }
+        Some(Commands::Helper { clean }) => {
+            if clean {
+                orphans::clean_orphans(&config, false)?;
+            } else {
+                anyhow::bail!("no operation specified (use -h for help)");
+            }
+        }
+        Some(Commands::Show { stats: _, json }) => {
+            let stats = stats::collect(&config).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                stats::print(&stats);
             }
         }
-        Some(Commands::Remove { packages }) => {
-            // Separate pacman flags from package names
-            let (pacman_flags, pkg_names): (Vec<String>, Vec<String>) =
-                packages.into_iter().partition(|arg| arg.starts_with('-'));
-
-            if !pkg_names.is_empty() {
-                let mut cmd = Command::new("sudo");
-                cmd.arg("pacman").arg("-R").arg("-s");
-
-                // Forward user-provided flags
-                for flag in pacman_flags {
-                    cmd.arg(flag);
+        Some(Commands::Downgrade { package }) => {
+            downgrade::downgrade(&package, &config).await?;
+        }
+        Some(Commands::History { action }) => match action {
+            None => history::list()?,
+            Some(HistoryAction::Undo { id }) => history::undo(id, &config)?,
+        },
+        Some(Commands::GetPkgbuild { targets }) => {
+            getpkgbuild::get_pkgbuilds(&targets, &std::env::current_dir()?).await?;
+        }
+        Some(Commands::Remove {
+            cascade,
+            nosave,
+            recursive,
+            unneeded,
+            common,
+            transaction,
+            packages,
+        }) => {
+            if !packages.is_empty() {
+                let mut cmd = privilege::pacman(&config, true)?;
+                // Dependencies are removed recursively by default
+                cmd.arg("-R")
+                    .args(vec!["--recursive"; recursive.max(1) as usize]);
+                if cascade {
+                    cmd.arg("--cascade");
+                }
+                if nosave {
+                    cmd.arg("--nosave");
                 }
+                if unneeded {
+                    cmd.arg("--unneeded");
+                }
+                cmd.args(common.to_args())
+                    .args(transaction.to_args())
+                    .args(&packages);
 
-                cmd.args(&pkg_names);
-                let status = cmd.status().context("Failed to execute sudo pacman -R")?;
+                let status = cmd.status().context("Failed to execute pacman -R")?;
                 if !status.success() {
                     std::process::exit(status.code().unwrap_or(1));
                 }
             }
         }
         Some(Commands::Query { args }) => {
-            let mut cmd = Command::new("pacman");
-            cmd.arg("-Q").args(&args);
+            let mut cmd = privilege::pacman(&config, false)?;
+            cmd.arg("-Q").args(passthrough_args('Q', &args));
             let status = cmd.status().context("Failed to execute pacman -Q")?;
             if !status.success() {
                 std::process::exit(status.code().unwrap_or(1));
             }
         }
         Some(Commands::Files { args }) => {
-            let mut cmd = Command::new("pacman");
-            cmd.arg("-F").args(&args);
+            let mut cmd = privilege::pacman(&config, false)?;
+            cmd.arg("-F").args(passthrough_args('F', &args));
             let status = cmd.status().context("Failed to execute pacman -F")?;
             if !status.success() {
                 std::process::exit(status.code().unwrap_or(1));
             }
         }
         Some(Commands::Deptest { args }) => {
-            let mut cmd = Command::new("pacman");
-            cmd.arg("-T").args(&args);
+            let mut cmd = privilege::pacman(&config, false)?;
+            cmd.arg("-T").args(passthrough_args('T', &args));
             let status = cmd.status().context("Failed to execute pacman -T")?;
             if !status.success() {
                 std::process::exit(status.code().unwrap_or(1));
             }
         }
         Some(Commands::Database { args }) => {
-            let mut cmd = Command::new("sudo");
-            cmd.arg("pacman").arg("-D").args(&args);
-            let status = cmd.status().context("Failed to execute sudo pacman -D")?;
+            let mut cmd = privilege::pacman(&config, true)?;
+            cmd.arg("-D").args(passthrough_args('D', &args));
+            let status = cmd.status().context("Failed to execute pacman -D")?;
             if !status.success() {
                 std::process::exit(status.code().unwrap_or(1));
             }
         }
-        Some(Commands::Upgrade { args }) => {
-            let mut cmd = Command::new("sudo");
-            cmd.arg("pacman").arg("-U").args(&args);
-            let status = cmd.status().context("Failed to execute sudo pacman -U")?;
+        Some(Commands::Upgrade {
+            common,
+            transaction,
+            upgrade,
+            files,
+        }) => {
+            let mut cmd = privilege::pacman(&config, true)?;
+            cmd.arg("-U")
+                .args(common.to_args())
+                .args(transaction.to_args())
+                .args(upgrade.to_args())
+                .args(&files);
+            let status = cmd.status().context("Failed to execute pacman -U")?;
             if !status.success() {
                 std::process::exit(status.code().unwrap_or(1));
             }
         }
+        Some(Commands::Config { .. }) => unreachable!("handled above"),
         None => {
             if !cli.query.is_empty() {
                 let query = cli.query.join(" ");
@@ -169,6 +383,192 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn handle_config_command(action: &ConfigAction, overrides: &[String]) -> Result<()> {
+    let config_path = config::Config::path().context("Could not determine config directory")?;
+
+    match action {
+        ConfigAction::List { origin } => {
+            let config = config::Config::load_layered(overrides)?;
+            for (key, value) in config.entries()? {
+                let value = config::Config::format_value(&value, false);
+                if *origin {
+                    println!(
+                        "{} = {} {}",
+                        key.bold(),
+                        value,
+                        format!("# {}", config.origin(&key)).dimmed()
+                    );
+                } else {
+                    println!("{} = {}", key.bold(), value);
+                }
+            }
+        }
+        ConfigAction::Get { key } => {
+            let config = config::Config::load_layered(overrides)?;
+            let value = config.get_value(key)?;
+            if !value.is_null() {
+                println!("{}", config::Config::format_value(&value, true));
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            config::Config::set_user_value(key, value)?;
+            let config = config::Config::load_layered(overrides)?;
+            println!(
+                ":: {} = {}",
+                key.bold(),
+                config::Config::format_value(&config.get_value(key)?, false)
+            );
+            let origin = config.origin(key);
+            if !origin.starts_with("user") && !origin.starts_with("default") {
+                println!(
+                    "{} {} is overridden by {}",
+                    "!!".yellow().bold(),
+                    key,
+                    origin
+                );
+            }
+        }
+        ConfigAction::Edit => {
+            if !config_path.exists() {
+                if let Some(dir) = config_path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                std::fs::write(
+                    &config_path,
+                    "# ax user configuration, overrides /etc/ax/config.toml\n\
+                     # See `ax config list` for all keys\n",
+                )?;
+            }
+            let editor = config::Config::load_layered(overrides)
+                .unwrap_or_default()
+                .get_editor();
+
+            loop {
+                interactive::open_editor(&editor, std::slice::from_ref(&config_path))?;
+                match config::Config::validate_file(&config_path) {
+                    Ok(_) => {
+                        println!("{}", ":: Configuration is valid.".green());
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("{} {:#}", "!!".red().bold(), e);
+                        if !interactive::prompt_confirm("Edit again?")? {
+                            anyhow::bail!("config.toml is invalid");
+                        }
+                    }
+                }
+            }
+        }
+        ConfigAction::Path => println!("{}", config_path.display()),
+    }
+
+    Ok(())
+}
+
+/// Forward a sync operation that ax does not handle itself (-Ss, -Si, -Sg,
+/// -Sl, -Sc, -Sp) to pacman. Search and info also cover the AUR.
+async fn sync_passthrough(
+    sync: &cli::SyncOptions,
+    pacman_flags: &[String],
+    targets: &[String],
+    config: &config::Config,
+) -> Result<()> {
+    let needs_root = sync.clean > 0 || sync.refresh > 0;
+
+    let mut repo_targets = targets.to_vec();
+    let mut aur_targets = Vec::new();
+    if sync.info > 0 && !targets.is_empty() {
+        let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
+        (repo_targets, aur_targets) = targets
+            .iter()
+            .cloned()
+            .partition(|t| arch_db.exists_in_repo(t));
+    }
+
+    let mut found = false;
+    // Skip pacman if all -Si targets are AUR packages
+    if !repo_targets.is_empty() || aur_targets.is_empty() {
+        let status = privilege::pacman(config, needs_root)?
+            .arg("-S")
+            .args(sync.to_args())
+            .args(pacman_flags)
+            .args(&repo_targets)
+            .status()
+            .context("Failed to execute pacman -S")?;
+        if !sync.search && !status.success() {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        found = status.success();
+    }
+
+    if sync.search && !targets.is_empty() {
+        // The AUR matches a single keyword, the other terms filter like pacman
+        let terms: Vec<String> = targets.iter().map(|t| t.to_lowercase()).collect();
+        let mut results: Vec<api::AurPackage> = api::search(&targets[0])
+            .await?
+            .into_iter()
+            .filter(|pkg| {
+                let haystack = format!("{} {}", pkg.name, pkg.description.as_deref().unwrap_or(""))
+                    .to_lowercase();
+                terms.iter().all(|term| haystack.contains(term))
+            })
+            .collect();
+        results.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for pkg in &results {
+            if sync.quiet {
+                println!("{}", pkg.name);
+                continue;
+            }
+            println!(
+                "{}/{} {} {}",
+                "aur".magenta().bold(),
+                pkg.name.bold(),
+                pkg.version.green(),
+                format!("(+{})", pkg.num_votes.unwrap_or(0)).cyan()
+            );
+            if let Some(desc) = &pkg.description {
+                println!("    {}", desc);
+            }
+        }
+        found |= !results.is_empty();
+    }
+
+    if !aur_targets.is_empty() {
+        // Invalid names cannot be in the AUR and are reported as not found
+        let names: Vec<pkgname::PkgName> = aur_targets
+            .iter()
+            .filter_map(|name| pkgname::PkgName::new(name).ok())
+            .collect();
+        let infos = api::get_info(&names).await?;
+        for target in &aur_targets {
+            match infos.iter().find(|pkg| &pkg.name == target) {
+                Some(pkg) => {
+                    println!("{:<16}: aur", "Repository".bold());
+                    println!("{:<16}: {}", "Name".bold(), pkg.name);
+                    println!("{:<16}: {}", "Version".bold(), pkg.version);
+                    println!(
+                        "{:<16}: {}",
+                        "Description".bold(),
+                        pkg.description.as_deref().unwrap_or("None")
+                    );
+                    println!("{:<16}: {}", "Votes".bold(), pkg.num_votes.unwrap_or(0));
+                    println!();
+                }
+                None => eprintln!("error: package '{}' was not found", target),
+            }
+        }
+        if infos.len() < aur_targets.len() {
+            std::process::exit(1);
+        }
+    }
+
+    if sync.search && !found {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
 async fn search_and_install(query: &str, config: &config::Config) -> Result<()> {
     let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
 
@@ -199,38 +599,129 @@ async fn search_and_install(query: &str, config: &config::Config) -> Result<()>
 
     let mut packages_to_install = Vec::new();
     for idx in selection {
-        packages_to_install.push(results[idx].name().to_string());
+        packages_to_install.push(pkgname::PkgName::new(results[idx].name())?);
     }
 
     // Default to no cleanbuild for interactive search for now, or we could prompt?
     // For now, let's assume false because I'm too lazy to add another prompt.
-    install_packages(&packages_to_install, config, &[]).await
+    install_packages(&packages_to_install, config, &[], &[]).await
 }
 
 async fn install_packages(
-    packages: &[String],
+    packages: &[pkgname::PkgName],
     config: &config::Config,
     pacman_flags: &[String],
+    force_rebuild: &[pkgname::PkgName],
 ) -> Result<()> {
     let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
 
     // NEW: Use DAG-based resolution
-    let plan = resolver::resolve_with_dag(packages, &arch_db, config).await?;
+    let plan = resolver::resolve_with_dag(packages, &arch_db, config, force_rebuild).await?;
+
+    // Packages confirmed for --ignorearch are built like configured ones
+    let mut config = config.clone();
+    for pkgbase in &plan.ignore_arch {
+        config
+            .packages
+            .entry(pkgbase.to_string())
+            .or_default()
+            .ignore_arch = true;
+    }
+    let config = &config;
 
     println!("\n{}", ":: Resolution complete!".green().bold());
     println!("   Repo packages: {}", plan.repo_deps.len());
     println!("   AUR packages: {}", plan.build_order.len());
 
+    let overrides: Vec<(&pkgname::PkgName, Vec<String>)> = plan
+        .build_order
+        .iter()
+        .map(|pkgbase| (pkgbase, config.package(pkgbase).summary()))
+        .filter(|(_, summary)| !summary.is_empty())
+        .collect();
+    if !overrides.is_empty() {
+        println!("\n{}", ":: Package overrides:".blue().bold());
+        for (pkgbase, summary) in overrides {
+            println!("   {}: {}", pkgbase.bold(), summary.join("; "));
+        }
+    }
+
+    // Flag risky PKGBUILD patterns before anything is built
+    scanner::check_packages(&plan.build_order, config)?;
+
+    // Review everything up front so the builds below can run unattended
+    if config.upfront_review {
+        review::review_all(&plan.build_order, config)?;
+    }
+
+    let mut run = history::HistoryEntry::new();
+    run.repo_deps = plan.repo_deps.clone();
+    run.build_order = plan.build_order.iter().map(|p| p.to_string()).collect();
+    run.new_repo_deps = plan
+        .repo_deps
+        .iter()
+        .filter(|dep| arch_db.get_installed_version(dep).is_none())
+        .cloned()
+        .collect();
+
+    let result = execute_plan(
+        plan,
+        &arch_db,
+        config,
+        pacman_flags,
+        force_rebuild,
+        &mut run,
+    );
+    run.finish(&result);
+    result
+}
+
+/// Install the repo dependencies, then build and install the AUR packages
+/// of `plan`, recording what was built in `run`
+fn execute_plan(
+    plan: resolver::ResolutionPlan,
+    arch_db: &arch::ArchDB,
+    config: &config::Config,
+    pacman_flags: &[String],
+    force_rebuild: &[pkgname::PkgName],
+    run: &mut history::HistoryEntry,
+) -> Result<()> {
+    // -Sw installs nothing: makepkg -s pulls in repo dependencies for the
+    // build and -r removes them again, and built AUR dependencies can only
+    // be handed to chroot builds
+    if config.build_only {
+        let aur_deps: Vec<&str> = plan
+            .build_order
+            .iter()
+            .filter(|pkgbase| !plan.targets.contains(*pkgbase))
+            .map(|pkgbase| pkgbase.as_str())
+            .collect();
+        let host_builds: Vec<&str> = plan
+            .build_order
+            .iter()
+            .filter(|pkgbase| !config.package(pkgbase).chroot)
+            .map(|pkgbase| pkgbase.as_str())
+            .collect();
+        if !aur_deps.is_empty() && !host_builds.is_empty() {
+            anyhow::bail!(
+                "-Sw does not install the AUR dependencies {}. Install them first, or set \
+                 `chroot = true` for {} to build against them in a clean chroot.",
+                aur_deps.join(", "),
+                host_builds.join(", ")
+            );
+        }
+    }
+
     // Phase 1: Install Official Deps
-    if !plan.repo_deps.is_empty() {
+    if !plan.repo_deps.is_empty() && !config.build_only {
         println!(
             "\n{}",
             ":: Installing official dependencies...".yellow().bold()
         );
         println!(":: Targets: {:?}", plan.repo_deps);
 
-        let mut pacman_cmd = Command::new("sudo");
-        pacman_cmd.arg("pacman").arg("-S").arg("--needed");
+        let mut pacman_cmd = privilege::pacman(config, true)?;
+        pacman_cmd.arg("-S").arg("--needed");
 
         for dep in &plan.repo_deps {
             pacman_cmd.arg(dep);
@@ -241,9 +732,7 @@ async fn install_packages(
             pacman_cmd.arg(flag);
         }
 
-        let status = pacman_cmd
-            .status()
-            .context("Failed to execute sudo pacman")?;
+        let status = pacman_cmd.status().context("Failed to execute pacman")?;
 
         if !status.success() {
             anyhow::bail!("Failed to install official dependencies. Aborting.");
@@ -258,11 +747,38 @@ async fn install_packages(
         );
         println!(":: Build order: {:?}", plan.build_order);
 
+        // Packages built so far, made available to chroot builds
+        let mut built_packages: Vec<std::path::PathBuf> = Vec::new();
+        // Packages built by -Sw, none of them installed
+        let mut kept_packages: Vec<std::path::PathBuf> = Vec::new();
+
         for pkgbase in plan.build_order {
             // build_package now returns the exact paths of packages to install
             loop {
-                match builder::build_package(&pkgbase, config, config.diff_viewer) {
+                match builder::build_package(
+                    &pkgbase,
+                    config,
+                    !config.upfront_review,
+                    // -Sw rebuilds targets whose packages exist already
+                    force_rebuild.contains(&pkgbase)
+                        || (config.build_only && plan.targets.contains(&pkgbase)),
+                    &built_packages,
+                ) {
                     Ok(package_paths) => {
+                        // -Sw keeps everything, dependencies go to later chroot builds
+                        if config.build_only {
+                            run.record_build(
+                                &pkgbase,
+                                &config.get_cache_dir().join(&pkgbase),
+                                &package_paths,
+                                arch_db,
+                                false,
+                            );
+                            built_packages.extend(package_paths.iter().cloned());
+                            kept_packages.extend(package_paths);
+                            break;
+                        }
+
                         // Install the built packages using exact paths from makepkg --packagelist
                         if !package_paths.is_empty() {
                             println!(
@@ -273,15 +789,44 @@ async fn install_packages(
                                     .collect::<Vec<_>>()
                             );
 
+                            // Dependencies new to the system are installed as such, so
+                            // they are found by the orphan cleanup once unneeded
+                            let pkgnames: Vec<String> = package_paths
+                                .iter()
+                                .filter_map(|p| p.file_name())
+                                .filter_map(|f| clean::artifact_pkgname(&f.to_string_lossy()))
+                                .collect();
+                            let is_target = plan.targets.contains(&pkgbase);
+                            let as_dep = !is_target
+                                && pkgnames
+                                    .iter()
+                                    .all(|name| arch_db.get_installed_version(name).is_none());
+
+                            // Recorded with the versions installed before this package
+                            run.record_build(
+                                &pkgbase,
+                                &config.get_cache_dir().join(&pkgbase),
+                                &package_paths,
+                                arch_db,
+                                false,
+                            );
+
                             // Retry loop for installation (Phase 10 / Item 12)
                             loop {
-                                let mut cmd = Command::new("sudo");
-                                cmd.arg("pacman").arg("-U"); // No --noconfirm: Allow interactive conflict resolution
+                                let mut cmd = privilege::pacman(config, true)?;
+                                cmd.arg("-U"); // No --noconfirm: Allow interactive conflict resolution
 
                                 // Forward user-provided pacman flags
                                 for flag in pacman_flags {
                                     cmd.arg(flag);
                                 }
+                                if as_dep
+                                    && !pacman_flags
+                                        .iter()
+                                        .any(|f| f == "--asdeps" || f == "--asexplicit")
+                                {
+                                    cmd.arg("--asdeps");
+                                }
 
                                 for p in &package_paths {
                                     cmd.arg(p);
@@ -310,6 +855,7 @@ async fn install_packages(
                                         }
                                         interactive::ErrorAction::Skip => {
                                             println!("{}", ":: Skipping package...".yellow());
+                                            run.skipped.push(pkgbase.to_string());
                                             break; // Break install loop
                                         }
                                         interactive::ErrorAction::Abort => {
@@ -317,7 +863,26 @@ async fn install_packages(
                                         }
                                     }
                                 } else {
-                                    break; 
+                                    run.mark_installed(&package_paths);
+                                    built_packages.extend(package_paths.iter().cloned());
+                                    let make_dep = as_dep && plan.make_deps.contains(&pkgbase);
+                                    if (make_dep || is_target)
+                                        && let Err(e) = orphans::record_install(&pkgnames, make_dep)
+                                    {
+                                        eprintln!(
+                                            "{} Failed to update make dependency database: {:#}",
+                                            "!!".yellow(),
+                                            e
+                                        );
+                                    }
+                                    if let Err(e) = devel::record_build(&pkgbase, config) {
+                                        eprintln!(
+                                            "{} Failed to update devel database: {:#}",
+                                            "!!".yellow(),
+                                            e
+                                        );
+                                    }
+                                    break;
                                 }
                             }
                         } else {
@@ -347,6 +912,7 @@ async fn install_packages(
                             }
                             interactive::ErrorAction::Skip => {
                                 println!("{}", ":: Skipping package...".yellow());
+                                run.skipped.push(pkgbase.to_string());
                                 break; 
                             }
                             interactive::ErrorAction::Abort => {
@@ -357,28 +923,30 @@ async fn install_packages(
                 }
             }
         }
+
+        if !kept_packages.is_empty() {
+            println!("\n{}", ":: Built packages (not installed):".green().bold());
+            for path in &kept_packages {
+                println!("   {}", path.display());
+            }
+        }
+
+        if config.clean_orphans && !config.build_only {
+            // Only what this or earlier runs installed for building
+            orphans::clean_orphans(config, true)?;
+        }
     }
 
     Ok(())
 }
 
-fn check_tools() -> Result<()> {
-    let tools = ["git", "pacman", "makepkg"];
+fn check_tools(config: &config::Config) -> Result<()> {
+    let makepkg = config.makepkg_bin.as_deref().unwrap_or("makepkg");
+    let tools = ["git", "pacman", makepkg];
     for tool in tools {
-        // Use 'command -v' instead of 'which' for better reliability
-        // Pass tool as argument to sh -c to prevent injection
-        let status = Command::new("sh")
-            .arg("-c")
-            .arg("command -v \"$1\"")
-            .arg("--")
-            .arg(tool)
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status();
-
-        if status.map(|s| !s.success()).unwrap_or(true) {
+        if !privilege::command_exists(tool) {
             anyhow::bail!(
-                "Required tool '{}' not found. Please install it:\n  sudo pacman -S {}",
+                "Required tool '{}' not found. Please install it:\n  pacman -S {}",
                 tool,
                 match tool {
                     "makepkg" => "base-devel",
@@ -390,6 +958,23 @@ fn check_tools() -> Result<()> {
     Ok(())
 }
 
+/// clap keeps bundled flags of pass-through operations whole (`-Qdtq`), so
+/// drop the operation letter, which pacman would reject when given twice
+fn passthrough_args(op: char, args: &[String]) -> Vec<String> {
+    let mut args = args.to_vec();
+    if let Some(first) = args.first_mut()
+        && !first.starts_with("--")
+        && let Some(flags) = first.strip_prefix('-').and_then(|f| f.strip_prefix(op))
+    {
+        if flags.is_empty() {
+            args.remove(0);
+        } else {
+            *first = format!("-{}", flags);
+        }
+    }
+    args
+}
+
 fn check_interactive() -> Result<bool> {
     // Check if we're running in an interactive terminal
     // This prevents sudo from hanging in non-interactive environments
@@ -408,34 +993,82 @@ fn check_interactive() -> Result<bool> {
     Ok(true)
 }
 
-fn preprocess_args(args: impl Iterator<Item = String>) -> Vec<String> {
-    let mut new_args = Vec::new();
-    let mut args_iter = args.into_iter();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Push the program name
-    if let Some(arg) = args_iter.next() {
-        new_args.push(arg);
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("ax").chain(args.iter().copied())).unwrap()
     }
 
-    // Check the first argument after the program name
-    if let Some(arg) = args_iter.next() {
-        // If it looks like a bundled short flag (e.g. -Syu, -Qs, -Scc)
-        if arg.starts_with('-') && !arg.starts_with("--") && arg.len() > 2 {
-            let cmd = arg.chars().nth(1).unwrap();
-            // Check if the first character matches a known command
-            if matches!(cmd, 'S' | 'R' | 'Q' | 'D' | 'F' | 'T' | 'U') {
-                new_args.push(format!("-{}", cmd));
-                new_args.push(format!("-{}", &arg[2..]));
-            } else {
-                new_args.push(arg);
-            }
-        } else {
-            new_args.push(arg);
+    #[test]
+    fn test_pacman_option_grammar() {
+        let Some(Commands::Sync {
+            sync,
+            upgrade,
+            packages,
+            ..
+        }) = parse(&["-Syyu", "--overwrite", "/usr/*", "foo", "--needed"]).command
+        else {
+            panic!("expected -S");
+        };
+        assert_eq!((sync.refresh, sync.sysupgrade), (2, 1));
+        assert_eq!(upgrade.to_args(), ["--needed", "--overwrite", "/usr/*"]);
+        assert_eq!(packages, ["foo"]);
+
+        let Some(Commands::Sync { packages, .. }) = parse(&["-S", "--", "-foo"]).command else {
+            panic!("expected -S");
+        };
+        assert_eq!(packages, ["-foo"]);
+
+        assert!(Cli::try_parse_from(["ax", "-S", "--no-such-flag", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_pacman_flags() {
+        let args = "-S --asdeps foo --needed --ignore a,b --ignore=c";
+        let Some(Commands::Sync {
+            upgrade, packages, ..
+        }) = parse(&args.split(' ').collect::<Vec<_>>()).command
+        else {
+            panic!("expected -S");
+        };
+        assert_eq!(packages, ["foo"]);
+        assert_eq!(upgrade.ignored(), ["a", "b", "c"]);
+        assert_eq!(
+            upgrade.to_args(),
+            ["--asdeps", "--ignore", "a,b", "--ignore", "c", "--needed"]
+        );
+
+        for (cli, expected) in [
+            (&["-Qdtq"][..], &["-dtq"][..]),
+            (&["-Q", "-dtq"], &["-dtq"]),
+            (&["-Qi", "foo"], &["-i", "foo"]),
+            (&["-Q"], &[]),
+        ] {
+            let Some(Commands::Query { args }) = parse(cli).command else {
+                panic!("expected -Q");
+            };
+            assert_eq!(passthrough_args('Q', &args), expected);
         }
     }
 
-    // Push the rest of the arguments
-    new_args.extend(args_iter);
+    #[test]
+    fn test_config_flags() {
+        // --config is pacman's, ax's overrides use --ax-config
+        let cli = parse(&["-Syu", "--config", "/etc/pacman-testing.conf"]);
+        assert!(cli.config_overrides.is_empty());
+        let Some(Commands::Sync { common, .. }) = cli.command else {
+            panic!("expected -S");
+        };
+        assert_eq!(common.to_args(), ["--config", "/etc/pacman-testing.conf"]);
+        assert_eq!(common.foreign_root(), None);
 
-    new_args
+        let cli = parse(&["--ax-config", "clean_build=true", "-S", "--root", "/mnt"]);
+        assert_eq!(cli.config_overrides, ["clean_build=true"]);
+        let Some(Commands::Sync { common, .. }) = cli.command else {
+            panic!("expected -S");
+        };
+        assert_eq!(common.foreign_root(), Some("--root"));
+    }
 }
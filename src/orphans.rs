@@ -0,0 +1,151 @@
+use crate::arch::ArchDB;
+use crate::config::{self, Config};
+use crate::privilege;
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// AUR packages ax installed only as make dependencies of other packages
+/// (`makedeps.json` in the data dir), labelled as such among the orphans.
+/// Packages made explicit since (`pacman -D --asexplicit`) are forgotten.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MakeDepsDb {
+    packages: BTreeSet<String>,
+}
+
+impl MakeDepsDb {
+    fn path() -> PathBuf {
+        Config::get_data_dir().join("makedeps.json")
+    }
+
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse make dependency database at {:?}", path))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&Self::path())
+    }
+
+    fn save_to(&self, path: &Path) -> Result<()> {
+        config::write_locked(path, &serde_json::to_string_pretty(self)?)
+    }
+
+    /// Remember `names` as installed for building only (`as_dep`), or
+    /// forget them because the user installed them explicitly
+    fn record(&mut self, names: &[String], as_dep: bool) {
+        for name in names {
+            if as_dep {
+                self.packages.insert(name.clone());
+            } else {
+                self.packages.remove(name);
+            }
+        }
+    }
+}
+
+/// Record installed packages in the make dependency database, see `MakeDepsDb::record`
+pub fn record_install(names: &[String], as_dep: bool) -> Result<()> {
+    let mut db = MakeDepsDb::load()?;
+    db.record(names, as_dep);
+    db.save()
+}
+
+/// The orphans to remove: all of them, or with `only_make_deps` just the
+/// AUR make dependencies ax installed itself
+fn removal_targets(
+    orphans: &[String],
+    make_deps: &BTreeSet<String>,
+    only_make_deps: bool,
+) -> Vec<String> {
+    orphans
+        .iter()
+        .filter(|name| !only_make_deps || make_deps.contains(*name))
+        .cloned()
+        .collect()
+}
+
+/// List orphaned dependencies, including AUR make dependencies ax
+/// installed, and remove them with `pacman -Rns` after confirmation.
+/// With `only_make_deps` (the automatic run after installing), orphans
+/// ax did not install as make dependencies are left alone.
+pub fn clean_orphans(config: &Config, only_make_deps: bool) -> Result<()> {
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    let mut db = MakeDepsDb::load()?;
+    db.packages.retain(|name| arch_db.is_installed_as_dep(name));
+
+    let orphans = removal_targets(&arch_db.get_orphans(), &db.packages, only_make_deps);
+    if orphans.is_empty() {
+        println!(":: No orphaned packages to remove.");
+        return db.save();
+    }
+
+    println!("\n{}", ":: Orphaned packages:".blue().bold());
+    for name in &orphans {
+        if db.packages.contains(name) {
+            println!("   {} {}", name.bold(), "(AUR make dependency)".cyan());
+        } else {
+            println!("   {}", name.bold());
+        }
+    }
+
+    // pacman lists the full removal set and asks for confirmation
+    let mut cmd = privilege::pacman(config, true)?;
+    cmd.args(["-R", "--nosave", "--recursive"]);
+    if config.no_confirm {
+        cmd.arg("--noconfirm");
+    }
+    let status = cmd
+        .args(&orphans)
+        .status()
+        .context("Failed to execute pacman -Rns")?;
+
+    if status.success() {
+        db.packages.retain(|name| !orphans.contains(name));
+    }
+    db.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_makedeps_db_round_trip() {
+        let tmp = crate::tempdir::TempDir::new("ax-makedeps-test").unwrap();
+        let path = tmp.path().join("makedeps.json");
+        assert!(MakeDepsDb::load_from(&path).unwrap().packages.is_empty());
+
+        let mut db = MakeDepsDb::default();
+        db.record(&["foo".to_string(), "bar".to_string()], true);
+        // Installed explicitly later
+        db.record(&["bar".to_string()], false);
+        db.save_to(&path).unwrap();
+
+        let db = MakeDepsDb::load_from(&path).unwrap();
+        assert_eq!(db.packages, BTreeSet::from(["foo".to_string()]));
+    }
+
+    #[test]
+    fn test_removal_targets() {
+        let orphans = ["foo".to_string(), "libbar".to_string()];
+        let make_deps = BTreeSet::from(["foo".to_string(), "gone".to_string()]);
+        assert_eq!(removal_targets(&orphans, &make_deps, true), ["foo"]);
+        assert_eq!(
+            removal_targets(&orphans, &make_deps, false),
+            ["foo", "libbar"]
+        );
+        assert!(removal_targets(&orphans, &BTreeSet::new(), true).is_empty());
+    }
+}
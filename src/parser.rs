@@ -9,8 +9,15 @@ pub struct PackageMetaData {
     pub depends: Vec<String>,
     pub make_depends: Vec<String>,
     pub validpgpkeys: Vec<String>,
+    pub sources: Vec<String>,
+    /// Checksum of each entry in `sources` (first algorithm present), or empty
+    pub checksums: Vec<String>,
+    /// Install scriptlets (`install=`) of the pkgbase and split packages
+    pub install: Vec<String>,
     pub arch: Vec<String>,
     pub pkgnames: Vec<String>,
+    /// Depends of each package in `pkgnames`, in the same order
+    pub package_depends: Vec<Vec<String>>,
 }
 
 pub fn clean_dependency(dep: &str) -> String {
@@ -20,12 +27,25 @@ pub fn clean_dependency(dep: &str) -> String {
     dep.to_string()
 }
 
+/// Parse `.SRCINFO` content, e.g. the output of `makepkg --printsrcinfo`
+/// or a `.SRCINFO` blob from an older commit.
+pub fn parse_srcinfo_str(content: &str) -> Result<PackageMetaData> {
+    let srcinfo: Srcinfo = content
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Failed to parse .SRCINFO: {:?}", e))?;
+    Ok(metadata_from_srcinfo(&srcinfo))
+}
+
 pub fn parse_srcinfo(path: &Path) -> Result<PackageMetaData> {
     let srcinfo_path = path.join(".SRCINFO");
 
     let srcinfo = Srcinfo::from_path(&srcinfo_path)
         .map_err(|e| anyhow::anyhow!("Failed to parse .SRCINFO at {:?}: {:?}", srcinfo_path, e))?;
 
+    Ok(metadata_from_srcinfo(&srcinfo))
+}
+
+fn metadata_from_srcinfo(srcinfo: &Srcinfo) -> PackageMetaData {
     let mut metadata = PackageMetaData {
         pkgbase: srcinfo.pkgbase().to_string(),
         version: srcinfo.version().to_string(),
@@ -50,27 +70,65 @@ pub fn parse_srcinfo(path: &Path) -> Result<PackageMetaData> {
         }
     }
 
+    // Collect sources and their checksums (global + arch-specific)
+    let all_sums = [
+        srcinfo.sha256sums(),
+        srcinfo.sha512sums(),
+        srcinfo.b2sums(),
+        srcinfo.sha384sums(),
+        srcinfo.sha224sums(),
+        srcinfo.sha1sums(),
+        srcinfo.md5sums(),
+    ];
+    for source_arch in srcinfo.source() {
+        if source_arch.arch().is_none() || source_arch.arch() == Some(current_arch) {
+            let sums: Vec<&str> = all_sums
+                .iter()
+                .find_map(|sums| sums.iter().find(|s| s.arch() == source_arch.arch()))
+                .map(|sums| sums.iter().collect())
+                .unwrap_or_default();
+
+            for (i, source) in source_arch.iter().enumerate() {
+                metadata.sources.push(source.to_string());
+                metadata
+                    .checksums
+                    .push(sums.get(i).copied().unwrap_or_default().to_string());
+            }
+        }
+    }
+
     // Collect validpgpkeys
     for key in srcinfo.valid_pgp_keys() {
         metadata.validpgpkeys.push(key.to_string());
     }
 
     // Iterate through all packages (base + split packages)
+    for pkg in std::iter::once(&srcinfo.pkg).chain(srcinfo.pkgs()) {
+        if let Some(install) = pkg.install()
+            && !metadata.install.iter().any(|i| i == install)
+        {
+            metadata.install.push(install.to_string());
+        }
+    }
+
     for pkg in srcinfo.pkgs() {
         metadata.pkgnames.push(pkg.pkgname().to_string());
 
         // Collect package-specific depends (global + arch-specific)
+        let mut package_depends = Vec::new();
         for depends_arch in pkg.depends() {
             if depends_arch.arch().is_none() || depends_arch.arch() == Some(current_arch) {
                 for depend in depends_arch.iter() {
                     let depend_str = depend.to_string();
                     if !metadata.depends.contains(&depend_str) {
-                        metadata.depends.push(depend_str);
+                        metadata.depends.push(depend_str.clone());
                     }
+                    package_depends.push(depend_str);
                 }
             }
         }
+        metadata.package_depends.push(package_depends);
     }
 
-    Ok(metadata)
+    metadata
 }
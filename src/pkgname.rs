@@ -0,0 +1,181 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Longest name accepted, the file name limit of common filesystems
+const MAX_LEN: usize = 255;
+
+/// A package or pkgbase name following makepkg's naming rules: ASCII
+/// letters, digits and `@._+-`, not starting with `-` or `.`. Such a name
+/// is always a single path component, so it is safe to join onto the build
+/// dir and to put into AUR URLs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PkgName(String);
+
+impl PkgName {
+    pub fn new(name: &str) -> Result<Self> {
+        if name.is_empty() {
+            anyhow::bail!("Invalid package name: name is empty");
+        }
+        if name.len() > MAX_LEN {
+            anyhow::bail!(
+                "Invalid package name '{}...': longer than {} characters",
+                name.chars().take(32).collect::<String>(),
+                MAX_LEN
+            );
+        }
+        if name.starts_with(['-', '.']) {
+            anyhow::bail!(
+                "Invalid package name '{}': must not start with '-' or '.'",
+                name
+            );
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !"@._+-".contains(*c))
+        {
+            anyhow::bail!(
+                "Invalid package name '{}': contains {:?} (allowed are letters, digits and @._+-)",
+                name.escape_debug(),
+                c
+            );
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    /// Validate all `names`, failing on the first invalid one
+    pub fn parse_all(names: &[String]) -> Result<Vec<Self>> {
+        names.iter().map(|name| Self::new(name)).collect()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for PkgName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for PkgName {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::new(&s)
+    }
+}
+
+impl From<PkgName> for String {
+    fn from(name: PkgName) -> String {
+        name.0
+    }
+}
+
+impl fmt::Display for PkgName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Deref for PkgName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for PkgName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for PkgName {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl PartialEq<str> for PkgName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<String> for PkgName {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+impl Borrow<str> for PkgName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_names() {
+        for name in [
+            "foo",
+            "foo-git",
+            "lib32-gcc-libs",
+            "python3.12",
+            "c++utils",
+            "foo@bar_1",
+        ] {
+            assert_eq!(PkgName::new(name).unwrap().as_str(), name);
+        }
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        for name in [
+            "..",
+            ".",
+            "../foo",
+            "../../etc/passwd",
+            "foo/../../bar",
+            "foo/bar",
+            "/etc",
+            ".hidden",
+            "foo\\bar",
+        ] {
+            assert!(PkgName::new(name).is_err(), "{} was accepted", name);
+        }
+
+        let base = Path::new("/home/user/.cache/ax");
+        let name = PkgName::new("foo..bar").unwrap();
+        assert_eq!(base.join(&name).parent(), Some(base));
+    }
+
+    #[test]
+    fn test_rejects_odd_names() {
+        for name in [
+            "", "-foo", "foo bar", "foo\nbar", "foo?bar", "foo#bar", "foo%2e", "föo",
+        ] {
+            assert!(PkgName::new(name).is_err(), "{:?} was accepted", name);
+        }
+        assert!(PkgName::new(&"a".repeat(MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_validates() {
+        assert!(serde_json::from_str::<PkgName>("\"foo\"").is_ok());
+        assert!(serde_json::from_str::<PkgName>("\"../foo\"").is_err());
+    }
+}
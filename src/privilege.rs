@@ -0,0 +1,218 @@
+use crate::config::Config;
+use anyhow::Result;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the sudo loop refreshes cached credentials
+const SUDO_LOOP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Escalation tools tried in order when `sudo_bin` is not configured
+const KNOWN_TOOLS: &[&str] = &["sudo", "doas", "run0", "pkexec"];
+
+/// Returns true if ax runs with an effective UID of 0
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Check if `tool` is available in PATH
+pub fn command_exists(tool: &str) -> bool {
+    // Use 'command -v' instead of 'which' for better reliability
+    // Pass tool as argument to sh -c to prevent injection
+    Command::new("sh")
+        .arg("-c")
+        .arg("command -v \"$1\"")
+        .arg("--")
+        .arg(tool)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// The escalation tool to prefix root commands with, or `None` if ax already
+/// runs as root or `sudo_bin = "none"`. Fails if no tool can be found.
+pub fn escalation_tool(config: &Config) -> Result<Option<String>> {
+    if is_root() {
+        return Ok(None);
+    }
+
+    match config.sudo_bin.as_deref() {
+        Some("none") => Ok(None),
+        Some(bin) if command_exists(bin) => Ok(Some(bin.to_string())),
+        Some(bin) => anyhow::bail!("sudo_bin `{}` was not found in PATH", bin),
+        None => match KNOWN_TOOLS.iter().find(|tool| command_exists(tool)) {
+            Some(tool) => Ok(Some(tool.to_string())),
+            None => anyhow::bail!(
+                "No privilege escalation tool found (tried {}). Install one, set `sudo_bin` \
+                 in config.toml, or run ax as root.",
+                KNOWN_TOOLS.join(", ")
+            ),
+        },
+    }
+}
+
+/// Build a command that runs `program` with root privileges
+pub fn root_command(config: &Config, program: &str) -> Result<Command> {
+    Ok(match escalation_tool(config)? {
+        Some(tool) => {
+            let mut cmd = Command::new(tool);
+            cmd.args(&config.sudo_flags).arg(program);
+            cmd
+        }
+        None => Command::new(program),
+    })
+}
+
+/// Build a `pacman` command, escalated if the operation needs root
+pub fn pacman(config: &Config, needs_root: bool) -> Result<Command> {
+    if needs_root {
+        root_command(config, "pacman")
+    } else {
+        Ok(Command::new("pacman"))
+    }
+}
+
+/// Build a `makepkg` command using the configured binary, makepkg.conf,
+/// `PKGDEST` and `SRCDEST`. makepkg refuses to run as root, so when ax
+/// runs as root the build is dropped to the configured `build_user`.
+pub fn makepkg(config: &Config) -> Result<Command> {
+    let bin = config.makepkg_bin.as_deref().unwrap_or("makepkg");
+
+    let mut cmd = if !is_root() {
+        Command::new(bin)
+    } else {
+        match config.build_user.as_deref() {
+            Some(user) => {
+                let mut cmd = Command::new("runuser");
+                cmd.args(["-u", user, "--", bin]);
+                cmd
+            }
+            None => anyhow::bail!(
+                "makepkg cannot run as root. Run ax as a regular user, or set \
+                 `build_user` in config.toml to an unprivileged user that owns the build dir."
+            ),
+        }
+    };
+
+    if let Some(conf) = &config.makepkg_conf {
+        cmd.arg("--config").arg(conf);
+    }
+    if let Some(dir) = &config.pkgdest {
+        cmd.env("PKGDEST", dir);
+    }
+    if let Some(dir) = &config.srcdest {
+        cmd.env("SRCDEST", dir);
+    }
+    // Sources are verified against the ax keyring, not the personal one
+    cmd.env("GNUPGHOME", crate::gpg::keyring_dir(config)?);
+    // makepkg -s/-r install and remove dependencies with the same tool as ax.
+    // Commands that need no root (e.g. --printsrcinfo) must not fail without one.
+    if let Ok(Some(tool)) = escalation_tool(config) {
+        cmd.env("PACMAN_AUTH", tool);
+    }
+    Ok(cmd)
+}
+
+/// Background thread keeping sudo credentials fresh during long build queues.
+/// The loop stops when this is dropped; on a signal it dies with the process.
+pub struct SudoLoop {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SudoLoop {
+    /// Validate credentials once (prompting if needed) and start refreshing
+    /// them in the background. Returns `None` if no sudo is involved.
+    pub fn start(config: &Config) -> Result<Option<Self>> {
+        let Some(tool) = escalation_tool(config)? else {
+            return Ok(None);
+        };
+        if !tool.ends_with("sudo") {
+            log::warn!("sudoloop is only supported with sudo, not {}", tool);
+            return Ok(None);
+        }
+
+        let status = sudo_command(&tool, &config.sudo_flags, &["-v"]).status()?;
+        if !status.success() {
+            anyhow::bail!("Failed to validate {} credentials", tool);
+        }
+
+        let flags = config.sudo_flags.clone();
+        Ok(Some(Self::spawn(SUDO_LOOP_INTERVAL, move || {
+            let _ = sudo_command(&tool, &flags, &["-n", "-v"])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        })))
+    }
+
+    /// Run `refresh` every `interval` until dropped
+    fn spawn(interval: Duration, refresh: impl Fn() + Send + 'static) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            // Wakes up on timeout to refresh, exits once the sender is dropped
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                refresh();
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+/// `<tool> <sudo_flags> <args>`, e.g. `sudo -v`
+fn sudo_command(tool: &str, flags: &[String], args: &[&str]) -> Command {
+    let mut cmd = Command::new(tool);
+    cmd.args(flags).args(args);
+    cmd
+}
+
+impl Drop for SudoLoop {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_sudo_command() {
+        let flags = vec!["-p".to_string(), "[ax] password: ".to_string()];
+        let cmd = sudo_command("/usr/bin/sudo", &flags, &["-n", "-v"]);
+        assert_eq!(cmd.get_program(), "/usr/bin/sudo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            ["-p", "[ax] password: ", "-n", "-v"]
+        );
+    }
+
+    #[test]
+    fn test_sudo_loop_stops_on_drop() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&refreshes);
+        let sudo_loop = SudoLoop::spawn(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+
+        // Dropping joins the thread, so no refresh can happen afterwards
+        drop(sudo_loop);
+        let count = refreshes.load(Ordering::SeqCst);
+        assert!(count > 0);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(refreshes.load(Ordering::SeqCst), count);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::arch::{ArchDB, InstalledFiles};
+use crate::elf;
+use anyhow::Result;
+use colored::*;
+use log::debug;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// A foreign pkgbase with at least one binary linked against a missing soname
+#[derive(Debug)]
+pub struct RebuildCandidate {
+    pub pkgbase: String,
+    /// Installed packages built from `pkgbase`, reinstalled by the rebuild
+    pub pkgnames: Vec<String>,
+    /// Human-readable reasons, e.g. "foo: /usr/bin/foo needs libicuuc.so.74"
+    pub reasons: Vec<String>,
+}
+
+/// Scan the files of all installed foreign packages for ELF `DT_NEEDED`
+/// entries that no installed package provides any more.
+pub fn find_broken_packages(arch_db: &ArchDB) -> Result<Vec<RebuildCandidate>> {
+    println!(
+        "{}",
+        ":: Checking AUR packages for broken libraries..."
+            .blue()
+            .bold()
+    );
+
+    let provided = arch_db.get_provided_libraries();
+    Ok(select_candidates(
+        &arch_db.get_foreign_package_files(),
+        &provided,
+        |file| {
+            // Skip symlinks, their targets are scanned on their own
+            match std::fs::symlink_metadata(file) {
+                Ok(meta) if meta.is_file() => {}
+                _ => return None,
+            }
+            match elf::needed_libraries(file) {
+                Ok(needed) => needed,
+                Err(e) => {
+                    debug!("Failed to read {}: {}", file.display(), e);
+                    None
+                }
+            }
+        },
+    ))
+}
+
+/// Group the packages with a `needed` library missing from `provided` by
+/// pkgbase, together with all installed packages of that pkgbase
+fn select_candidates(
+    packages: &[InstalledFiles],
+    provided: &HashSet<String>,
+    needed: impl Fn(&Path) -> Option<Vec<String>>,
+) -> Vec<RebuildCandidate> {
+    let mut broken: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for pkg in packages {
+        for file in &pkg.files {
+            for lib in needed(file).unwrap_or_default() {
+                if !provided.contains(&lib) {
+                    broken.entry(&pkg.base).or_default().push(format!(
+                        "{}: {} needs {}",
+                        pkg.name,
+                        file.display(),
+                        lib
+                    ));
+                }
+            }
+        }
+    }
+
+    broken
+        .into_iter()
+        .map(|(pkgbase, reasons)| RebuildCandidate {
+            pkgbase: pkgbase.to_string(),
+            pkgnames: packages
+                .iter()
+                .filter(|pkg| pkg.base == pkgbase)
+                .map(|pkg| pkg.name.clone())
+                .collect(),
+            reasons,
+        })
+        .collect()
+}
+
+pub fn show_candidates(candidates: &[RebuildCandidate]) {
+    if candidates.is_empty() {
+        println!("{}", ":: No AUR packages need a rebuild.".green());
+        return;
+    }
+
+    println!("\n{}", ":: Packages needing a rebuild:".yellow().bold());
+    for candidate in candidates {
+        println!("   {}", candidate.pkgbase.cyan().bold());
+        for reason in &candidate.reasons {
+            println!("      {} {}", "->".yellow(), reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn installed(name: &str, base: &str, files: &[&str]) -> InstalledFiles {
+        InstalledFiles {
+            name: name.to_string(),
+            base: base.to_string(),
+            files: files.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn test_select_candidates() {
+        let packages = [
+            installed("foo-cli", "foo", &["/usr/bin/foo"]),
+            installed("foo-docs", "foo", &["/usr/share/doc/foo/index.html"]),
+            installed("bar", "bar", &["/usr/bin/bar"]),
+            installed("baz", "baz", &["/usr/bin/baz"]),
+        ];
+        let provided: HashSet<String> = ["libc.so.6", "libicuuc.so.75"]
+            .iter()
+            .map(|lib| lib.to_string())
+            .collect();
+        let needed = |file: &Path| {
+            let libs: &[&str] = match file.to_str()? {
+                "/usr/bin/foo" => &["libc.so.6", "libicuuc.so.74"],
+                "/usr/bin/bar" => &["libc.so.6", "libicuuc.so.75"],
+                "/usr/bin/baz" => &["libboost_system.so.1.83.0"],
+                _ => return None,
+            };
+            Some(libs.iter().map(|lib| lib.to_string()).collect())
+        };
+
+        let candidates = select_candidates(&packages, &provided, needed);
+        let summary: Vec<(&str, &[String])> = candidates
+            .iter()
+            .map(|c| (c.pkgbase.as_str(), c.pkgnames.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("baz", &["baz".to_string()][..]),
+                ("foo", &["foo-cli".to_string(), "foo-docs".to_string()][..]),
+            ]
+        );
+        assert_eq!(
+            candidates[1].reasons,
+            ["foo-cli: /usr/bin/foo needs libicuuc.so.74"]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::arch::ArchDB;
+use crate::config::CloneRecovery;
 use crate::parser::PackageMetaData;
-use anyhow::Result;
+use crate::pkgname::PkgName;
+use anyhow::{Context, Result};
 use colored::*;
 
 
@@ -13,18 +15,24 @@ use log::debug;
 #[derive(Debug)]
 pub struct ResolutionPlan {
     pub repo_deps: Vec<String>,
-    pub build_order: Vec<String>,
+    pub build_order: Vec<PkgName>,
+    /// Pkgbases providing the requested packages, as opposed to dependencies
+    pub targets: HashSet<PkgName>,
+    /// Pkgbases the user agreed to build with `makepkg --ignorearch` this run
+    pub ignore_arch: Vec<PkgName>,
+    /// Pkgbases only needed as `makedepends` of other AUR packages
+    pub make_deps: HashSet<PkgName>,
 }
 
 /// Phase 1: Collect all package names that need resolution
 async fn collect_all_packages(
-    packages: &[String],
+    packages: &[PkgName],
     arch_db: &ArchDB,
     config: &crate::config::Config,
 ) -> Result<(HashMap<String, PackageMetaData>, HashSet<String>)> {
     let mut aur_packages = HashMap::new();
     let mut repo_packages = HashSet::new();
-    let mut to_process = packages.to_vec();
+    let mut to_process: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
     let mut processed = HashSet::new();
 
     while let Some(pkg) = to_process.pop() {
@@ -44,7 +52,18 @@ async fn collect_all_packages(
 
         // Must be in AUR - don't insert now, allow metadata parsing to drive insertion
 
-        // Clone and parse PKGBUILD to get dependencies
+        // Dependency names come from .SRCINFO files and end up in paths and URLs
+        let name = PkgName::new(&pkg).context("Cannot resolve dependency")?;
+
+        // AUR repos are named after the pkgbase, known for installed split packages
+        let pkg = match arch_db.get_installed_pkgbase(&name) {
+            Some(pkgbase) => PkgName::new(&pkgbase).context("Cannot resolve dependency")?,
+            None => name,
+        };
+        if aur_packages.contains_key(pkg.as_str()) {
+            continue;
+        }
+
         // Clone and parse PKGBUILD to get dependencies
         let cache_base = config.get_cache_dir();
 
@@ -53,11 +72,19 @@ async fn collect_all_packages(
             let aur_url = format!("https://aur.archlinux.org/{}.git", pkg);
             crate::git_ops::clone_repo(&aur_url, &cache_path)?;
         } else {
-            crate::git_ops::pull_repo(&cache_path)?;
+            let patches = config.package(&pkg).patch_paths();
+            crate::git_ops::pull_repo(&cache_path, &patches, |changes| {
+                choose_clone_recovery(&pkg, changes, config)
+            })?;
         }
+        crate::git_ops::apply_patches(&cache_path, &config.package(&pkg).patch_paths())?;
 
         // Parse .SRCINFO for dependencies
         if let Ok(metadata) = crate::parser::parse_srcinfo(&cache_path) {
+            for name in std::iter::once(&metadata.pkgbase).chain(&metadata.pkgnames) {
+                PkgName::new(name).with_context(|| format!("Invalid .SRCINFO of {}", pkg))?;
+            }
+
             // Store by pkgbase to ensure consistent lookup in Phase 3
             aur_packages.insert(metadata.pkgbase.clone(), metadata.clone());
             
@@ -73,11 +100,72 @@ async fn collect_all_packages(
     Ok((aur_packages, repo_packages))
 }
 
+/// Decide how to update the clone of `pkgbase` that has local changes or
+/// diverged: `--reset-clones`, the remembered choice, or a prompt
+fn choose_clone_recovery(
+    pkgbase: &str,
+    changes: &crate::git_ops::LocalChanges,
+    config: &crate::config::Config,
+) -> Result<CloneRecovery> {
+    if config.reset_clones {
+        println!(":: Resetting clone of {} to the AUR", pkgbase.cyan());
+        return Ok(CloneRecovery::Reset);
+    }
+    if let Some(recovery) = config.package(pkgbase).recovery {
+        debug!(
+            "Remembered clone recovery for {}: {}",
+            pkgbase,
+            recovery.as_str()
+        );
+        return Ok(recovery);
+    }
+
+    if changes.diverged {
+        println!(
+            "{} The clone of {} has diverged from the AUR",
+            "!!".yellow().bold(),
+            pkgbase.cyan()
+        );
+    }
+    if !changes.modified.is_empty() {
+        println!(
+            "{} The clone of {} has local changes: {}",
+            "!!".yellow().bold(),
+            pkgbase.cyan(),
+            changes.modified.join(", ")
+        );
+    }
+
+    // Building the outdated local version unasked would go unnoticed
+    if config.no_confirm {
+        anyhow::bail!(
+            "Not updating the clone of {} without confirmation. Use --reset-clones to reset it \
+             to the AUR, or set `recovery` in [packages.{}].",
+            pkgbase,
+            pkgbase
+        );
+    }
+
+    let recovery = crate::interactive::prompt_clone_recovery(!changes.modified.is_empty())?;
+    if crate::interactive::prompt_default_no(&format!("Remember this choice for {}?", pkgbase))? {
+        crate::config::Config::set_package_value(
+            pkgbase,
+            "recovery",
+            toml::Value::String(recovery.as_str().to_string()),
+        )?;
+    }
+    Ok(recovery)
+}
+
 /// Phase 2: Build dependency graph and get topological order
+///
+/// Pkgbases listed in `force_rebuild` are kept in the build order even if
+/// the installed version is up to date (e.g. after a soname bump).
 pub async fn resolve_with_dag(
-    packages: &[String],
+    packages: &[PkgName],
     arch_db: &ArchDB,
     config: &crate::config::Config,
+    force_rebuild: &[PkgName],
 ) -> Result<ResolutionPlan> {
     println!(
         "{}",
@@ -153,11 +241,45 @@ pub async fn resolve_with_dag(
         }
     };
 
+    let targets: HashSet<PkgName> = packages
+        .iter()
+        .filter_map(|pkg| match pkgbase_map.get(pkg.as_str()) {
+            Some(pkgbase) => PkgName::new(pkgbase).ok(),
+            None => aur_packages.contains_key(pkg.as_str()).then(|| pkg.clone()),
+        })
+        .collect();
+
+    // All pkgbases were validated when their .SRCINFO was read
+    let build_order = build_order
+        .iter()
+        .map(|pkgbase| PkgName::new(pkgbase))
+        .collect::<Result<Vec<_>>>()?;
+
     // Filter out packages that are already installed and up-to-date
-    let final_build_order: Vec<String> = build_order
+    let final_build_order: Vec<PkgName> = build_order
         .into_iter()
         .filter(|pkgbase| {
-            if let Some(metadata) = aur_packages.get(pkgbase) {
+            // Like pacman's IgnorePkg: never upgrade or rebuild once installed
+            if config.package(pkgbase).ignore
+                && aur_packages.get(pkgbase.as_str()).is_some_and(|m| {
+                    m.pkgnames
+                        .iter()
+                        .any(|name| arch_db.get_installed_version(name).is_some())
+                })
+            {
+                println!(
+                    "{} {} {}",
+                    ":: Skipping".yellow(),
+                    pkgbase.bold(),
+                    "(ignored in config)".yellow()
+                );
+                return false;
+            }
+            // -Sw builds its targets even if they are installed and up to date
+            if force_rebuild.contains(pkgbase) || (config.build_only && targets.contains(pkgbase)) {
+                return true;
+            }
+            if let Some(metadata) = aur_packages.get(pkgbase.as_str()) {
                 // Check if any package in the split package set is installed
                 // Usually check the main package or all of them.
                 // Simplified: Check if *all* pkgnames in this base are installed and up to date?
@@ -165,41 +287,54 @@ pub async fn resolve_with_dag(
                 // Conservative approach: If ANY pkgname in the base is NOT installed or OUTDATED, build.
                 // If ALL represent packages are installed AND up to date, skip.
 
-                let is_vcs = pkgbase.ends_with("-git")
-                    || pkgbase.ends_with("-hg")
-                    || pkgbase.ends_with("-svn")
-                    || pkgbase.ends_with("-bzr")
-                    || pkgbase.ends_with("-cvs")
-                    || pkgbase.ends_with("-darcs")
-                    || pkgbase.ends_with("-fossil");
-
-                let mut all_up_to_date = true;
-                for pkgname in &metadata.pkgnames {
-                    match arch_db.get_installed_version(pkgname) {
-                        Some(ver) => {
-                            // If VCS package, just being installed is enough
-                            if !is_vcs
-                                && crate::arch::ArchDB::vercmp(&ver, &metadata.version)
-                                    != std::cmp::Ordering::Equal
-                            {
-                                all_up_to_date = false;
-                                break;
+                let is_vcs = metadata.sources.iter().any(|s| crate::scanner::is_vcs(s));
+
+                // By default a VCS package only needs to be installed. With
+                // vcs_pkgver, run pkgver() to get the real version to compare.
+                // That runs PKGBUILD code, so only for clones already reviewed;
+                // a changed PKGBUILD is compared by its AUR version instead.
+                let mut version = metadata.version.clone();
+                let mut compare_version = !is_vcs;
+                let cache_path = config.get_cache_dir().join(pkgbase);
+                if is_vcs
+                    && config.vcs_pkgver
+                    && metadata
+                        .pkgnames
+                        .iter()
+                        .all(|name| arch_db.get_installed_version(name).is_some())
+                {
+                    if !crate::git_ops::is_reviewed(&cache_path) {
+                        debug!("Not running pkgver() of unreviewed {}", pkgbase);
+                        compare_version = true;
+                    } else {
+                        match crate::builder::resolve_vcs_version(pkgbase, &cache_path, config) {
+                            Ok(ver) => {
+                                version = ver;
+                                compare_version = true;
                             }
-                        }
-                        None => {
-                            all_up_to_date = false;
-                            break;
+                            Err(e) => eprintln!(
+                                "{} Could not determine latest version of {}: {:#}",
+                                "!! Warning:".yellow(),
+                                pkgbase,
+                                e
+                            ),
                         }
                     }
                 }
 
-                if all_up_to_date {
+                let installed: Vec<Option<String>> = metadata
+                    .pkgnames
+                    .iter()
+                    .map(|pkgname| arch_db.get_installed_version(pkgname))
+                    .collect();
+
+                if !needs_build(&installed, &version, compare_version) {
                     debug!("Skipping {} (up to date)", pkgbase);
                     println!(
                         "{} {} {}",
                         ":: Skipping".yellow(),
                         pkgbase.bold(),
-                        format!("(up to date: {})", metadata.version).green()
+                        format!("(up to date: {})", version).green()
                     );
                     return false;
                 }
@@ -208,8 +343,134 @@ pub async fn resolve_with_dag(
         })
         .collect();
 
+    let ignore_arch = check_architectures(&final_build_order, &aur_packages, config)?;
+    let make_deps = make_only_deps(&aur_packages, &pkgbase_map, &targets);
+
     Ok(ResolutionPlan {
         repo_deps: repo_packages.into_iter().collect(),
         build_order: final_build_order,
+        targets,
+        ignore_arch,
+        make_deps,
     })
 }
+
+/// Whether a pkgbase with the `installed` versions of its pkgnames needs to
+/// be built: some pkgname is missing or, with `compare_version`, older than
+/// `candidate`. Newer installs (e.g. VCS packages built from a later commit
+/// than the AUR's `pkgver`) are up to date.
+fn needs_build(installed: &[Option<String>], candidate: &str, compare_version: bool) -> bool {
+    installed.iter().any(|version| match version {
+        Some(version) => {
+            compare_version
+                && crate::arch::ArchDB::vercmp(version, candidate) == std::cmp::Ordering::Less
+        }
+        None => true,
+    })
+}
+
+/// Pkgbases that some AUR package lists in `makedepends` but none in
+/// `depends`, and that were not requested themselves
+fn make_only_deps(
+    aur_packages: &HashMap<String, crate::parser::PackageMetaData>,
+    pkgbase_map: &HashMap<String, String>,
+    targets: &HashSet<PkgName>,
+) -> HashSet<PkgName> {
+    let pkgbase_of = |dep: &String| pkgbase_map.get(&crate::parser::clean_dependency(dep));
+    let runtime: HashSet<&String> = aur_packages
+        .values()
+        .flat_map(|m| &m.depends)
+        .filter_map(pkgbase_of)
+        .collect();
+    aur_packages
+        .values()
+        .flat_map(|m| &m.make_depends)
+        .filter_map(pkgbase_of)
+        .filter(|pkgbase| !runtime.contains(pkgbase))
+        .filter_map(|pkgbase| PkgName::new(pkgbase).ok())
+        .filter(|pkgbase| !targets.contains(pkgbase))
+        .collect()
+}
+
+/// Report pkgbases whose `arch` does not include pacman's architecture.
+/// Those without `--ignorearch` or `ignore_arch` in their config need
+/// confirmation; the confirmed ones are returned.
+fn check_architectures(
+    build_order: &[PkgName],
+    aur_packages: &HashMap<String, PackageMetaData>,
+    config: &crate::config::Config,
+) -> Result<Vec<PkgName>> {
+    let system = crate::arch::system_architectures();
+    let mut unconfirmed = Vec::new();
+    for pkgbase in build_order {
+        let Some(metadata) = aur_packages
+            .values()
+            .find(|m| m.pkgbase == pkgbase.as_str())
+        else {
+            continue;
+        };
+        if crate::arch::supports_arch(&metadata.arch, &system) {
+            continue;
+        }
+
+        let ignored = config.ignore_arch || config.package(pkgbase).ignore_arch;
+        eprintln!(
+            "{} {} is only available for {} (system: {}){}",
+            "!!".yellow().bold(),
+            pkgbase.cyan(),
+            metadata.arch.join(" "),
+            system.join(" "),
+            if ignored {
+                ", building with --ignorearch"
+            } else {
+                ""
+            }
+        );
+        if !ignored {
+            unconfirmed.push(pkgbase.clone());
+        }
+    }
+
+    if unconfirmed.is_empty() {
+        return Ok(unconfirmed);
+    }
+    let names: Vec<&str> = unconfirmed.iter().map(|p| p.as_str()).collect();
+    if config.no_confirm {
+        anyhow::bail!(
+            "{} not available for {} (use --ignorearch or set ignore_arch = true in [packages.<pkgbase>])",
+            names.join(", "),
+            system.join(" ")
+        );
+    }
+    if !crate::interactive::prompt_default_no("Build anyway with makepkg --ignorearch?")? {
+        anyhow::bail!("Unsupported architecture: {}", names.join(", "));
+    }
+    if crate::interactive::prompt_default_no("Remember this choice?")? {
+        for pkgbase in &unconfirmed {
+            crate::config::Config::set_package_value(
+                pkgbase,
+                "ignore_arch",
+                toml::Value::Boolean(true),
+            )?;
+        }
+    }
+    Ok(unconfirmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needs_build() {
+        let installed = |v: &str| vec![Some(v.to_string())];
+
+        // An installed VCS build ahead of the AUR's pkgver is up to date
+        assert!(!needs_build(&installed("1.2.r40-1"), "1.2.r10-1", true));
+        assert!(!needs_build(&installed("1.2-1"), "1.2-1", true));
+        assert!(needs_build(&installed("1.1-1"), "1.2-1", true));
+        assert!(!needs_build(&installed("1.1-1"), "1.2-1", false));
+        let split = [Some("1.2-1".to_string()), None];
+        assert!(needs_build(&split, "1.2-1", true));
+    }
+}
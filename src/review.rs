@@ -0,0 +1,184 @@
+use crate::config::Config;
+use crate::git_ops;
+use crate::interactive;
+use crate::pkgname::PkgName;
+use crate::tempdir::TempDir;
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What the user gets to review for one pkgbase
+struct ReviewItem {
+    pkgbase: String,
+    /// Diff since the last reviewed commit, or `None` for first-time packages
+    diff: Option<String>,
+    /// PKGBUILD and `.install` files, shown for first-time packages
+    files: Vec<PathBuf>,
+    /// Result of the configured local patches, empty if there are none
+    patched: String,
+}
+
+impl ReviewItem {
+    fn collect(pkgbase: &str, config: &Config) -> Result<Self> {
+        let cache_path = config.get_cache_dir().join(pkgbase);
+        let patched = if config.package(pkgbase).patches.is_empty() {
+            String::new()
+        } else {
+            git_ops::get_local_changes(&cache_path)?
+        };
+
+        if config.diff_viewer && git_ops::get_reviewed_commit(&cache_path).is_some() {
+            return Ok(Self {
+                pkgbase: pkgbase.to_string(),
+                diff: Some(git_ops::get_diff(&cache_path)?),
+                files: vec![],
+                patched,
+            });
+        }
+
+        Ok(Self {
+            pkgbase: pkgbase.to_string(),
+            diff: None,
+            files: review_files(&cache_path)?,
+            patched,
+        })
+    }
+
+    fn is_unchanged(&self) -> bool {
+        self.diff.as_ref().is_some_and(|d| d.is_empty())
+    }
+}
+
+/// The PKGBUILD followed by any `.install` scriptlets in the clone
+pub fn review_files(cache_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![cache_path.join("PKGBUILD")];
+
+    let mut installs: Vec<PathBuf> = fs::read_dir(cache_path)
+        .with_context(|| format!("Failed to read {:?}", cache_path))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "install"))
+        .collect();
+    installs.sort();
+    files.extend(installs);
+
+    Ok(files)
+}
+
+/// Review phase run once after resolution: show every diff (or the full
+/// PKGBUILD and `.install` files of first-time packages) in one pager or
+/// editor session, then ask a single confirmation before building.
+/// Packages are marked as reviewed once the user confirms, unless the
+/// review itself was skipped.
+/// Packages with `skip_review` set in their `[packages]` entry are left out.
+pub fn review_all(build_order: &[PkgName], config: &Config) -> Result<()> {
+    let to_review: Vec<&PkgName> = build_order
+        .iter()
+        .filter(|pkgbase| !config.package(pkgbase).skip_review)
+        .collect();
+    if config.no_confirm || to_review.is_empty() {
+        return Ok(());
+    }
+
+    let items = to_review
+        .into_iter()
+        .map(|pkgbase| ReviewItem::collect(pkgbase, config))
+        .collect::<Result<Vec<_>>>()?;
+
+    println!("\n{}", ":: Packages to review:".blue().bold());
+    for item in &items {
+        let state = match &item.diff {
+            None => "new".yellow(),
+            Some(d) if d.is_empty() => "unchanged".green(),
+            Some(_) => "changed".red(),
+        };
+        println!("   {} ({})", item.pkgbase.bold(), state);
+    }
+
+    let reviewed = if items.iter().all(|item| item.is_unchanged()) {
+        println!(":: No changes since last review.");
+        true
+    } else {
+        match interactive::prompt_review_mode()? {
+            interactive::ReviewMode::Pager => show_in_pager(&items).map(|()| true)?,
+            interactive::ReviewMode::Editor => show_in_editor(&items, config).map(|()| true)?,
+            interactive::ReviewMode::Skip => false,
+        }
+    };
+
+    if !interactive::prompt_continue()? {
+        anyhow::bail!("Build aborted by user.");
+    }
+
+    if reviewed {
+        for item in &items {
+            git_ops::mark_reviewed(&config.get_cache_dir().join(&item.pkgbase))?;
+        }
+    }
+    Ok(())
+}
+
+fn show_in_pager(items: &[ReviewItem]) -> Result<()> {
+    let mut out = String::new();
+
+    for item in items.iter().filter(|item| !item.is_unchanged()) {
+        out.push_str(&format!(
+            "{}\n",
+            format!("==> {}", item.pkgbase).blue().bold()
+        ));
+        match &item.diff {
+            Some(diff) => out.push_str(diff),
+            None => {
+                for file in &item.files {
+                    let name = file.file_name().unwrap_or_default().to_string_lossy();
+                    out.push_str(&format!("{}\n", format!("--- {}", name).bold()));
+                    out.push_str(&fs::read_to_string(file).unwrap_or_default());
+                }
+            }
+        }
+        if !item.patched.is_empty() {
+            out.push_str(&format!("{}\n", "--- Local patches".bold()));
+            out.push_str(&item.patched);
+        }
+        out.push('\n');
+    }
+
+    interactive::show_in_pager(&out)
+}
+
+/// Open all diffs and files in the editor. Diffs are written into a private
+/// temporary directory; files of first-time packages are opened in the
+/// clone, so edits to them are kept for the build.
+fn show_in_editor(items: &[ReviewItem], config: &Config) -> Result<()> {
+    let review_dir = TempDir::new("ax-review")?;
+    let review_dir = review_dir.path();
+
+    let mut files = Vec::new();
+    for item in items.iter().filter(|item| !item.is_unchanged()) {
+        match &item.diff {
+            Some(_) => {
+                // Regenerate the diff without colors for the editor
+                colored::control::set_override(false);
+                let diff = git_ops::get_diff(&config.get_cache_dir().join(&item.pkgbase));
+                colored::control::unset_override();
+
+                let path = review_dir.join(format!("{}.diff", item.pkgbase));
+                fs::write(&path, diff?)?;
+                files.push(path);
+            }
+            None => files.extend(item.files.iter().cloned()),
+        }
+
+        if !item.patched.is_empty() {
+            colored::control::set_override(false);
+            let patched = git_ops::get_local_changes(&config.get_cache_dir().join(&item.pkgbase));
+            colored::control::unset_override();
+
+            let path = review_dir.join(format!("{}.local.diff", item.pkgbase));
+            fs::write(&path, patched?)?;
+            files.push(path);
+        }
+    }
+
+    interactive::open_editor(&config.get_editor(), &files)
+}
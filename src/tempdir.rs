@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Attempts to find an unused name before giving up
+const ATTEMPTS: usize = 16;
+
+/// Private (0700) directory below the system temp dir, removed on drop.
+/// Its name is random and it is created exclusively, so nobody can prepare
+/// the path (or a symlink in its place) in advance.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Result<Self> {
+        let base = std::env::temp_dir();
+        for _ in 0..ATTEMPTS {
+            let path = base.join(format!("{}-{:016x}", prefix, random()));
+            match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create {}", path.display()));
+                }
+            }
+        }
+        anyhow::bail!(
+            "Failed to create a temporary directory in {}",
+            base.display()
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Random number from std's randomly seeded hasher keys
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_private_and_removed() {
+        let first = TempDir::new("ax-test").unwrap();
+        let second = TempDir::new("ax-test").unwrap();
+        assert_ne!(first.path(), second.path());
+
+        let mode = std::fs::metadata(first.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
@@ -1,10 +1,37 @@
 use crate::api;
 use crate::arch;
+use crate::devel;
+use crate::pkgname::PkgName;
 use anyhow::{Context, Result};
 use colored::*;
+use std::collections::HashSet;
 
+/// AUR packages that have an update available
+pub struct Updates {
+    /// Package names with a newer version in the AUR
+    pub packages: Vec<PkgName>,
+    /// Devel pkgbases whose upstream VCS head moved since they were built
+    pub devel: Vec<PkgName>,
+}
+
+impl Updates {
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty() && self.devel.is_empty()
+    }
+
+    /// All targets to hand to the resolver
+    pub fn targets(&self) -> Vec<PkgName> {
+        self.packages
+            .iter()
+            .chain(self.devel.iter())
+            .cloned()
+            .collect()
+    }
+}
 
-pub async fn check_updates(config: &crate::config::Config) -> Result<Vec<String>> {
+/// AUR and devel updates of installed packages, except those in `ignore`
+/// (`--ignore`) or ignored in the config
+pub async fn check_updates(config: &crate::config::Config, ignore: &[String]) -> Result<Updates> {
     println!("{}", ":: Searching for AUR updates...".blue().bold());
 
     let arch_db = arch::ArchDB::new().context("Failed to initialize ALPM")?;
@@ -12,14 +39,23 @@ pub async fn check_updates(config: &crate::config::Config) -> Result<Vec<String>
 
     if foreign_pkgs.is_empty() {
         println!(":: No foreign packages installed.");
-        return Ok(vec![]);
+        return Ok(Updates {
+            packages: vec![],
+            devel: vec![],
+        });
     }
 
-    let pkg_names: Vec<String> = foreign_pkgs.iter().map(|p| p.name.clone()).collect();
+    // Names from the local database are valid, anything else is skipped
+    let pkg_names: Vec<PkgName> = foreign_pkgs
+        .iter()
+        .filter_map(|p| PkgName::new(&p.name).ok())
+        .collect();
     let remote_pkgs = api::get_info(&pkg_names).await?;
 
     let mut updates = Vec::new();
     let mut update_names = Vec::new();
+    // Pkgbases rebuilt for a new AUR version anyway
+    let mut update_bases = HashSet::new();
 
     // Create a map for faster lookup
     let local_map: std::collections::HashMap<String, String> = foreign_pkgs
@@ -28,48 +64,66 @@ pub async fn check_updates(config: &crate::config::Config) -> Result<Vec<String>
         .collect();
 
     for remote in remote_pkgs {
-        if let Some(local_ver) = local_map.get(&remote.name)
+        if let Some(local_ver) = local_map.get(remote.name.as_str())
             && alpm::vercmp(local_ver.as_str(), remote.version.as_str()) == std::cmp::Ordering::Less
         {
-            updates.push((remote.name.clone(), local_ver.clone(), remote.version));
-            update_names.push(remote.name);
+            // Both become build targets, paths and clone URLs
+            let (name, pkgbase) = match remote.pkgname().and_then(|n| Ok((n, remote.pkgbase()?))) {
+                Ok(names) => names,
+                Err(e) => {
+                    eprintln!("{} Skipping AUR result: {:#}", "!!".yellow(), e);
+                    continue;
+                }
+            };
+            let reason = if ignore.contains(&remote.name) {
+                Some("--ignore")
+            } else if config.package(&pkgbase).ignore {
+                Some("ignored in config")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                println!(
+                    "{} {} {}",
+                    ":: Ignoring".yellow(),
+                    remote.name.bold(),
+                    format!("{} -> {} ({})", local_ver, remote.version, reason).yellow()
+                );
+                continue;
+            }
+            updates.push((name.to_string(), local_ver.clone(), remote.version));
+            update_names.push(name);
+            update_bases.insert(pkgbase);
         }
     }
 
-    // Check VCS packages
-    // Include -git, -hg, -svn, -bzr, -darcs, -cvs, -nightly, -dev (common suffixes)
-    let vcs_suffixes = [
-        "-git", "-hg", "-svn", "-bzr", "-darcs", "-cvs", "-nightly", "-dev",
-    ];
+    // Check devel packages against the upstream heads recorded in the devel DB
+    let mut devel_updates = Vec::new();
+    if config.devel {
+        println!("{}", ":: Checking devel packages...".blue().bold());
+        let db = devel::DevelDb::load()?;
+        let installed_bases: HashSet<String> = arch_db.get_foreign_pkgbases().into_iter().collect();
 
-    // Resolve Cache Dir: Config > XDG > HOME
-    let cache_base = config.get_cache_dir();
-
-    for pkg in &foreign_pkgs {
-        if vcs_suffixes.iter().any(|s| pkg.name.ends_with(s)) {
-            // Check if we already added it (e.g. AUR version bump)
-            if update_names.contains(&pkg.name) {
-                continue;
-            }
-
-            let cache_path = cache_base.join(&pkg.name);
-            if cache_path.exists() {
-                if let Ok(true) = crate::git_ops::check_vcs_update(&cache_path) {
-                    updates.push((
-                        pkg.name.clone(),
-                        pkg.version.clone(),
-                        "latest-commit".to_string(),
-                    ));
-                    update_names.push(pkg.name.clone());
+        for pkgbase in devel::check_updates(&db, &installed_bases) {
+            // The devel database is a plain file, so its keys are untrusted
+            let pkgbase = match PkgName::new(&pkgbase) {
+                Ok(pkgbase) => pkgbase,
+                Err(e) => {
+                    eprintln!("{} Skipping devel entry: {:#}", "!!".yellow(), e);
+                    continue;
                 }
-            } else {
-                // Warn about missing cache for VCS packages
-                println!(
-                    "{} VCS cache missing for {}, skipping update check.",
-                    "::".yellow(),
-                    pkg.name.bold()
-                );
+            };
+            if config.package(&pkgbase).ignore
+                || update_bases.contains(&pkgbase)
+                || ignore.iter().any(|ignored| *ignored == pkgbase.as_str())
+            {
+                continue;
             }
+            let version = arch_db
+                .get_installed_base_version(&pkgbase)
+                .unwrap_or_else(|| "?".to_string());
+            updates.push((pkgbase.to_string(), version, "latest-commit".to_string()));
+            devel_updates.push(pkgbase);
         }
     }
 
@@ -82,5 +136,8 @@ pub async fn check_updates(config: &crate::config::Config) -> Result<Vec<String>
         }
     }
 
-    Ok(update_names)
+    Ok(Updates {
+        packages: update_names,
+        devel: devel_updates,
+    })
 }
@@ -0,0 +1,384 @@
+use crate::config::Config;
+use crate::interactive;
+use crate::parser::{self, PackageMetaData};
+use crate::scanner;
+use anyhow::{Context, Result};
+use colored::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Top-level directories packages normally install into
+const USUAL_DIRS: &[&str] = &["usr", "etc", "opt", "var", "srv", "boot"];
+/// Install paths that grant privileges or run code for every user
+const SENSITIVE_PATHS: &[&str] = &[
+    "etc/sudoers",
+    "etc/doas.conf",
+    "etc/pam.d/",
+    "etc/security/",
+    "usr/lib/security/",
+    "etc/polkit-1/",
+    "usr/share/polkit-1/rules.d/",
+    "etc/ld.so.preload",
+    "etc/ld.so.conf.d/",
+    "etc/profile.d/",
+    "etc/environment",
+    "etc/cron",
+    "etc/ssh/",
+    "etc/systemd/system/",
+    "etc/udev/rules.d/",
+];
+
+/// Metadata makepkg wrote into a package's `.PKGINFO`
+#[derive(Debug, Default, PartialEq)]
+pub struct PkgInfo {
+    pub pkgname: String,
+    pub pkgbase: String,
+    pub pkgver: String,
+    pub arch: String,
+    pub depends: Vec<String>,
+}
+
+/// A file of a package as listed by `bsdtar -tv`
+#[derive(Debug, PartialEq)]
+pub struct FileEntry {
+    /// `ls -l` style mode, e.g. "-rwsr-xr-x"
+    pub mode: String,
+    pub path: String,
+}
+
+pub fn parse_pkginfo(content: &str) -> PkgInfo {
+    let mut info = PkgInfo::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        let value = value.to_string();
+        match key.trim() {
+            "pkgname" => info.pkgname = value,
+            "pkgbase" => info.pkgbase = value,
+            "pkgver" => info.pkgver = value,
+            "arch" => info.arch = value,
+            "depend" => info.depends.push(value),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Parse one line of `bsdtar -tv` output:
+/// `-rwxr-xr-x  0 root   root   12345 Jan  1 12:00 usr/bin/foo`
+pub fn parse_listing_line(line: &str) -> Option<FileEntry> {
+    let mut rest = line.trim_start();
+    let mut mode = "";
+    // mode, links, owner, group, size, month, day, time or year
+    for i in 0..8 {
+        let end = rest.find(char::is_whitespace)?;
+        if i == 0 {
+            mode = &rest[..end];
+        }
+        rest = rest[end..].trim_start();
+    }
+    let path = rest.split_once(" -> ").map_or(rest, |(path, _)| path);
+    if path.is_empty() {
+        return None;
+    }
+    Some(FileEntry {
+        mode: mode.to_string(),
+        path: path.trim_start_matches("./").to_string(),
+    })
+}
+
+/// Warnings about where and how a package installs its files
+pub fn file_warnings(entries: &[FileEntry]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for entry in entries {
+        // Package metadata like .PKGINFO and .INSTALL is not installed
+        if entry.path.starts_with('.') && !entry.path.contains('/') {
+            continue;
+        }
+        let is_dir = entry.mode.starts_with('d');
+        let top = entry.path.split('/').next().unwrap_or_default();
+
+        if !USUAL_DIRS.contains(&top) {
+            if !is_dir || entry.path.trim_end_matches('/') == top {
+                warnings.push(format!("Installs into unusual path /{}", entry.path));
+            }
+            continue;
+        }
+        if is_dir {
+            continue;
+        }
+        if SENSITIVE_PATHS.iter().any(|p| entry.path.starts_with(p)) {
+            warnings.push(format!("Installs /{}", entry.path));
+        }
+
+        let mode = entry.mode.as_bytes();
+        if matches!(mode.get(3), Some(b's' | b'S')) {
+            warnings.push(format!("Installs setuid file /{}", entry.path));
+        }
+        if matches!(mode.get(6), Some(b's' | b'S')) {
+            warnings.push(format!("Installs setgid file /{}", entry.path));
+        }
+    }
+    warnings
+}
+
+/// Compare a built package with the reviewed .SRCINFO. A package that does
+/// not belong to the pkgbase is an error, other differences are warnings.
+/// With `vcs`, a different pkgver is expected (pkgver() ran during the build).
+pub fn compare(info: &PkgInfo, metadata: &PackageMetaData, vcs: bool) -> Result<Vec<String>> {
+    if !info.pkgbase.is_empty() && info.pkgbase != metadata.pkgbase {
+        anyhow::bail!(
+            "{} was built from pkgbase {}, expected {}",
+            info.pkgname,
+            info.pkgbase,
+            metadata.pkgbase
+        );
+    }
+    let Some(idx) = metadata.pkgnames.iter().position(|n| *n == info.pkgname) else {
+        anyhow::bail!(
+            "{} is not a package of {} (.SRCINFO lists {})",
+            info.pkgname,
+            metadata.pkgbase,
+            metadata.pkgnames.join(", ")
+        );
+    };
+
+    let mut warnings = Vec::new();
+    if info.pkgver != metadata.version {
+        // pkgver() only changes the pkgver, never the epoch or pkgrel
+        let release = |version: &str| {
+            let epoch = version.split_once(':').map(|(epoch, _)| epoch.to_string());
+            let pkgrel = version.rsplit_once('-').map(|(_, rel)| rel.to_string());
+            (epoch, pkgrel)
+        };
+        if !vcs || release(&info.pkgver) != release(&metadata.version) {
+            warnings.push(format!(
+                "Version {} differs from {} in .SRCINFO",
+                info.pkgver, metadata.version
+            ));
+        }
+    }
+
+    if info.arch != "any" && !metadata.arch.iter().any(|a| *a == info.arch || a == "any") {
+        warnings.push(format!(
+            "Architecture {} is not in .SRCINFO ({})",
+            info.arch,
+            metadata.arch.join(" ")
+        ));
+    }
+
+    let expected: HashSet<&str> = metadata
+        .package_depends
+        .get(idx)
+        .map(|deps| deps.iter().map(String::as_str).collect())
+        .unwrap_or_default();
+    let actual: HashSet<&str> = info.depends.iter().map(String::as_str).collect();
+    let mut added: Vec<&str> = actual.difference(&expected).copied().collect();
+    let mut removed: Vec<&str> = expected.difference(&actual).copied().collect();
+    added.sort();
+    removed.sort();
+    if !added.is_empty() {
+        warnings.push(format!(
+            "Depends on {} which .SRCINFO does not list",
+            added.join(", ")
+        ));
+    }
+    if !removed.is_empty() {
+        warnings.push(format!(
+            "Does not depend on {} listed in .SRCINFO",
+            removed.join(", ")
+        ));
+    }
+
+    Ok(warnings)
+}
+
+fn bsdtar(flags: &str, path: &Path, members: &[&str]) -> Result<String> {
+    let output = Command::new("bsdtar")
+        .arg(flags)
+        .arg(path)
+        .args(members)
+        .output()
+        .context("Failed to execute bsdtar")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub fn read_pkginfo(path: &Path) -> Result<PkgInfo> {
+    let info = parse_pkginfo(&bsdtar("-xOf", path, &[".PKGINFO"])?);
+    if info.pkgname.is_empty() {
+        anyhow::bail!("{} has no .PKGINFO", path.display());
+    }
+    Ok(info)
+}
+
+pub fn list_files(path: &Path) -> Result<Vec<FileEntry>> {
+    Ok(bsdtar("-tvf", path, &[])?
+        .lines()
+        .filter_map(parse_listing_line)
+        .collect())
+}
+
+/// Inspect the packages built from the clone at `cache_path` before they
+/// are installed. Missing files and packages of another pkgbase fail; other
+/// findings are listed and need confirmation, or abort `--noconfirm` runs
+/// if `scan_blocks_noconfirm` is set.
+pub fn check_artifacts(paths: &[PathBuf], cache_path: &Path, config: &Config) -> Result<()> {
+    let metadata = parser::parse_srcinfo(cache_path)?;
+    let vcs = metadata.sources.iter().any(|s| scanner::is_vcs(s));
+
+    let mut findings: Vec<(String, Vec<String>)> = Vec::new();
+    for path in paths {
+        if !path.is_file() {
+            anyhow::bail!("Built package {} does not exist", path.display());
+        }
+        let file_name = path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let info = read_pkginfo(path)?;
+
+        let mut warnings = compare(&info, &metadata, vcs)?;
+        warnings.extend(file_warnings(&list_files(path)?));
+        if !warnings.is_empty() {
+            findings.push((file_name, warnings));
+        }
+    }
+
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "\n{} {}",
+        "!!".yellow().bold(),
+        "Built packages need attention:".bold()
+    );
+    for (file_name, warnings) in &findings {
+        eprintln!("   {}", file_name.bold());
+        for warning in warnings {
+            eprintln!("      - {}", warning);
+        }
+    }
+
+    // -Sw only hands the packages out, so ask about keeping them instead
+    let (action, question) = if config.build_only {
+        ("keep", "Keep the packages anyway?")
+    } else {
+        ("install", "Install anyway?")
+    };
+    if config.no_confirm {
+        if config.scan_blocks_noconfirm {
+            anyhow::bail!(
+                "Refusing to {} {} with --noconfirm",
+                action,
+                metadata.pkgbase
+            );
+        }
+        return Ok(());
+    }
+    if !interactive::prompt_default_no(question)? {
+        anyhow::bail!("{} aborted by user.", metadata.pkgbase);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pkginfo() {
+        let info = parse_pkginfo(
+            "# Generated by makepkg 6.1.0\npkgname = foo\npkgbase = foo\npkgver = 1:2.0-3\n\
+             pkgdesc = a = b\narch = x86_64\ndepend = glibc\ndepend = bar>=1.0\n",
+        );
+        assert_eq!(
+            info,
+            PkgInfo {
+                pkgname: "foo".into(),
+                pkgbase: "foo".into(),
+                pkgver: "1:2.0-3".into(),
+                arch: "x86_64".into(),
+                depends: vec!["glibc".into(), "bar>=1.0".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_file_warnings() {
+        let entries: Vec<FileEntry> = [
+            "-rw-r--r--  0 root   root     512 Jan  1 12:00 .PKGINFO",
+            "drwxr-xr-x  0 root   root       0 Jan  1 12:00 usr/",
+            "-rwxr-xr-x  0 root   root   12345 Jan  1 12:00 usr/bin/foo",
+            "-rwsr-xr-x  0 root   root   12345 Jan  1  2024 usr/bin/foo helper",
+            "lrwxrwxrwx  0 root   root       0 Jan  1 12:00 usr/bin/bar -> foo",
+            "-r--r-----  0 root   root      40 Jan  1 12:00 etc/sudoers.d/foo",
+            "drwxr-xr-x  0 root   root       0 Jan  1 12:00 etc/pam.d/",
+            "-rw-r--r--  0 root   root      40 Jan  1 12:00 root/.bashrc",
+        ]
+        .iter()
+        .filter_map(|line| parse_listing_line(line))
+        .collect();
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[4].path, "usr/bin/bar");
+
+        assert_eq!(
+            file_warnings(&entries),
+            [
+                "Installs setuid file /usr/bin/foo helper",
+                "Installs /etc/sudoers.d/foo",
+                "Installs into unusual path /root/.bashrc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let metadata = PackageMetaData {
+            pkgbase: "foo".into(),
+            version: "1.0-1".into(),
+            arch: vec!["x86_64".into()],
+            pkgnames: vec!["foo".into(), "foo-docs".into()],
+            package_depends: vec![vec!["glibc".into()], vec![]],
+            ..Default::default()
+        };
+        let info = PkgInfo {
+            pkgname: "foo".into(),
+            pkgbase: "foo".into(),
+            pkgver: "1.0-1".into(),
+            arch: "x86_64".into(),
+            depends: vec!["glibc".into()],
+        };
+        assert!(compare(&info, &metadata, false).unwrap().is_empty());
+
+        let vcs = PkgInfo {
+            pkgver: "1.0.r5.gabc-1".into(),
+            ..info
+        };
+        assert!(compare(&vcs, &metadata, true).unwrap().is_empty());
+        assert_eq!(compare(&vcs, &metadata, false).unwrap().len(), 1);
+
+        let odd = PkgInfo {
+            pkgver: "1.0-1".into(),
+            arch: "aarch64".into(),
+            depends: vec!["evil".into()],
+            ..vcs
+        };
+        assert_eq!(compare(&odd, &metadata, false).unwrap().len(), 3);
+
+        let other = PkgInfo {
+            pkgname: "bar".into(),
+            ..odd
+        };
+        assert!(compare(&other, &metadata, false).is_err());
+    }
+}